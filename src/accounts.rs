@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use aws_config::{Region, SdkConfig};
+use aws_config::sts::AssumeRoleProvider;
+use aws_credential_types::Credentials;
+use aws_credential_types::provider::SharedCredentialsProvider;
+use serde::Deserialize;
+
+const SESSION_NAME: &str = "psmerge";
+
+/// Another account to fetch the same prefixes & secrets from, via a role.
+#[derive(Debug, Deserialize)]
+pub struct AccountSpec {
+    /// Its properties are namespaced under `accounts.<name>.`
+    pub name: String,
+    role_arn: String,
+    external_id: Option<String>,
+    session_name: Option<String>,
+    /// Region to use in that account, if different from the global one.
+    region: Option<String>,
+}
+
+/// Returns an SDK config with credentials for the role.
+pub async fn assume_role(sdk_config: &SdkConfig, role_arn: &str, external_id: Option<&str>, session_name: Option<&str>) -> SdkConfig {
+    let mut builder = AssumeRoleProvider::builder(role_arn)
+        .configure(sdk_config)
+        .session_name(session_name.unwrap_or(SESSION_NAME));
+    if let Some(id) = external_id {
+        builder = builder.external_id(id);
+    }
+    let provider = builder.build().await;
+
+    sdk_config.to_builder()
+        .credentials_provider(SharedCredentialsProvider::new(provider))
+        .build()
+}
+
+type RoleKey = (Option<String>, String, Option<String>, Option<String>);
+
+/// Configs for the roles sources assume (by region, role, external ID &
+/// session name), so each role gets one credentials provider however many
+/// prefixes & secrets use it. Only for sources sharing a base config.
+#[derive(Default)]
+pub struct RoleConfigs {
+    configs: tokio::sync::Mutex<HashMap<RoleKey, SdkConfig>>,
+}
+
+impl RoleConfigs {
+    pub async fn get(&self, sdk_config: &SdkConfig, role_arn: &str, external_id: Option<&str>, session_name: Option<&str>) -> SdkConfig {
+        let key = (sdk_config.region().map(|r| r.to_string()), role_arn.to_owned(),
+            external_id.map(str::to_owned), session_name.map(str::to_owned));
+        let mut configs = self.configs.lock().await;
+        if let Some(config) = configs.get(&key) {
+            return config.clone();
+        }
+        let config = assume_role(sdk_config, role_arn, external_id, session_name).await;
+        configs.insert(key, config.clone());
+        config
+    }
+}
+
+/// Reads an MFA code from the terminal.
+pub fn prompt_mfa_code(mfa_serial: &str) -> Result<String> {
+    if !std::io::stdin().is_terminal() {
+        bail!("Role requires MFA ({}) but there's no terminal to prompt on, use --mfa-token", mfa_serial);
+    }
+
+    eprint!("MFA code for {}: ", mfa_serial);
+    std::io::stderr().flush()?;
+    let mut code = String::new();
+    std::io::stdin().read_line(&mut code)?;
+    Ok(code.trim().to_owned())
+}
+
+/// Credentials for the role with an MFA code, fetched now (and not
+/// refreshed, since the code can't be reused).
+pub async fn assume_role_with_mfa(sdk_config: &SdkConfig, role_arn: &str, external_id: Option<&str>, session_name: Option<&str>,
+                                  mfa_serial: &str, token_code: &str) -> Result<Credentials> {
+    let output = aws_sdk_sts::Client::new(sdk_config).assume_role()
+        .role_arn(role_arn)
+        .role_session_name(session_name.unwrap_or(SESSION_NAME))
+        .set_external_id(external_id.map(str::to_owned))
+        .serial_number(mfa_serial)
+        .token_code(token_code)
+        .send().await
+        .with_context(|| format!("Error assuming role {} with MFA", role_arn))?;
+    let creds = output.credentials
+        .ok_or_else(|| anyhow!("No credentials returned assuming role {}", role_arn))?;
+
+    Ok(Credentials::new(creds.access_key_id, creds.secret_access_key, Some(creds.session_token),
+        SystemTime::try_from(creds.expiration).ok(), "psmerge-mfa"))
+}
+
+/// Returns an SDK config with credentials for the account's role.
+pub async fn account_config(sdk_config: &SdkConfig, spec: &AccountSpec) -> SdkConfig {
+    let sdk_config = match &spec.region {
+        Some(region) => sdk_config.to_builder().region(Region::new(region.clone())).build(),
+        None => sdk_config.clone()
+    };
+    assume_role(&sdk_config, &spec.role_arn, spec.external_id.as_deref(), spec.session_name.as_deref()).await
+}
+
+pub fn namespace(spec: &AccountSpec, data: HashMap<String, String>) -> HashMap<String, String> {
+    data.into_iter()
+        .map(|(k, v)| (format!("accounts.{}.{}", spec.name, k), v))
+        .collect()
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use aws_config::SdkConfig;
+use aws_sdk_acm::primitives::Blob;
+use pkcs8::{EncryptedPrivateKeyInfo, LineEnding, SecretDocument};
+use serde::Deserialize;
+
+use crate::keyformat::KeyPrefix;
+
+const PRIVATE_KEY_LABEL: &str = "PRIVATE KEY";
+
+/// An ACM (exportable) or ACM Private CA certificate. The PEM blocks are
+/// stored as `<key>.certificate`, `<key>.chain` and, for ACM,
+/// `<key>.private_key`.
+#[derive(Debug, Deserialize)]
+pub struct CertificateSpec {
+    arn: String,
+    key: String,
+}
+
+impl KeyPrefix for CertificateSpec {
+    fn key_prefix(&self) -> Option<String> {
+        Some(self.key.clone())
+    }
+}
+
+fn random_passphrase() -> Result<String> {
+    let mut bytes = [0u8; 24];
+    getrandom::fill(&mut bytes).map_err(|e| anyhow!("Failed to generate passphrase: {}", e))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn decrypt_private_key(pem: &str, passphrase: &str) -> Result<String> {
+    let (_label, doc) = SecretDocument::from_pem(pem).map_err(|e| anyhow!("Invalid private key PEM: {}", e))?;
+    let info = EncryptedPrivateKeyInfo::try_from(doc.as_bytes()).map_err(|e| anyhow!("Invalid encrypted private key: {}", e))?;
+    let key = info.decrypt(passphrase).map_err(|e| anyhow!("Failed to decrypt private key: {}", e))?;
+    let pem = key.to_pem(PRIVATE_KEY_LABEL, LineEnding::LF).map_err(|e| anyhow!("Failed to encode private key: {}", e))?;
+    Ok(pem.to_string())
+}
+
+async fn export_acm_certificate(client: &aws_sdk_acm::Client, spec: &CertificateSpec, data: &mut HashMap<String, String>) -> Result<()> {
+    // The exported key is encrypted with a passphrase of our choosing
+    let passphrase = random_passphrase()?;
+
+    let result = client.export_certificate()
+        .certificate_arn(&spec.arn)
+        .passphrase(Blob::new(passphrase.as_bytes()))
+        .send().await?;
+
+    let certificate = result.certificate.ok_or_else(|| anyhow!("No certificate returned"))?;
+    let private_key = result.private_key.ok_or_else(|| anyhow!("No private key returned"))?;
+
+    data.insert(format!("{}.certificate", spec.key), certificate);
+    if let Some(chain) = result.certificate_chain {
+        data.insert(format!("{}.chain", spec.key), chain);
+    }
+    data.insert(format!("{}.private_key", spec.key), decrypt_private_key(&private_key, &passphrase)?);
+
+    Ok(())
+}
+
+async fn get_pca_certificate(client: &aws_sdk_acmpca::Client, spec: &CertificateSpec, data: &mut HashMap<String, String>) -> Result<()> {
+    // Certificate ARNs are nested under their CA's ARN
+    let ca_arn = match spec.arn.find("/certificate/") {
+        Some(pos) => &spec.arn[..pos],
+        None => return Err(anyhow!("Not an ACM Private CA certificate ARN"))
+    };
+
+    let result = client.get_certificate()
+        .certificate_authority_arn(ca_arn)
+        .certificate_arn(&spec.arn)
+        .send().await?;
+
+    let certificate = result.certificate.ok_or_else(|| anyhow!("No certificate returned"))?;
+
+    data.insert(format!("{}.certificate", spec.key), certificate);
+    if let Some(chain) = result.certificate_chain {
+        data.insert(format!("{}.chain", spec.key), chain);
+    }
+
+    Ok(())
+}
+
+pub async fn get_certificate_properties(config: &SdkConfig, certificates: &[CertificateSpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    let acm_client = aws_sdk_acm::Client::new(config);
+    let pca_client = aws_sdk_acmpca::Client::new(config);
+
+    for spec in certificates {
+        // Private CA doesn't hold the private keys of certificates it issues
+        let result = if spec.arn.contains(":acm-pca:") {
+            get_pca_certificate(&pca_client, spec, &mut data).await
+        } else {
+            export_acm_certificate(&acm_client, spec, &mut data).await
+        };
+        result.with_context(|| format!("Failed to get certificate {}", spec.arn))?;
+    }
+
+    Ok(data)
+}
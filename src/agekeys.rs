@@ -0,0 +1,24 @@
+use std::io::Read;
+use std::path::Path;
+
+use age::armor::ArmoredReader;
+use age::{Decryptor, Identity, IdentityFile};
+use anyhow::{anyhow, Context, Result};
+
+/// Reads the age identities (private keys) in a file, one per line.
+pub fn load_identities(path: &Path) -> Result<Vec<Box<dyn Identity>>> {
+    let file = IdentityFile::from_file(path.display().to_string())
+        .with_context(|| format!("Error reading age identities {}", path.display()))?;
+    file.into_identities()
+        .map_err(|e| anyhow!("Error reading age identities {}: {}", path.display(), e))
+}
+
+/// Decrypts an age file, armored or not, with whichever identity fits.
+pub fn decrypt(identities: &[Box<dyn Identity>], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let decryptor = Decryptor::new(ArmoredReader::new(ciphertext))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))?;
+
+    let mut plaintext = Vec::new();
+    reader.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
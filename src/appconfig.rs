@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use aws_config::SdkConfig;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::keyformat::KeyPrefix;
+use crate::model::flatten_value;
+
+/// A freeform hosted configuration or feature flag profile. Identifiers may
+/// be names or IDs.
+#[derive(Debug, Deserialize)]
+pub struct ProfileSpec {
+    application: String,
+    environment: String,
+    profile: String,
+    /// Prefix for its keys. Required for plain text profiles.
+    key: Option<String>,
+}
+
+impl KeyPrefix for ProfileSpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+impl ProfileSpec {
+    fn description(&self) -> String {
+        format!("{}/{}/{}", self.application, self.environment, self.profile)
+    }
+}
+
+async fn fetch_profile(client: &aws_sdk_appconfigdata::Client, spec: &ProfileSpec) -> Result<(Vec<u8>, Option<String>)> {
+    let session = client.start_configuration_session()
+        .application_identifier(&spec.application)
+        .environment_identifier(&spec.environment)
+        .configuration_profile_identifier(&spec.profile)
+        .send().await?;
+    let token = session.initial_configuration_token
+        .ok_or_else(|| anyhow!("No configuration token returned"))?;
+
+    let latest = client.get_latest_configuration()
+        .configuration_token(token)
+        .send().await?;
+
+    Ok((latest.configuration.map(|b| b.into_inner()).unwrap_or_default(), latest.content_type))
+}
+
+pub async fn get_appconfig_properties(config: &SdkConfig, profiles: &[ProfileSpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    if profiles.is_empty() {
+        return Ok(data);
+    }
+
+    let client = aws_sdk_appconfigdata::Client::new(config);
+
+    for spec in profiles {
+        let (body, content_type) = fetch_profile(&client, spec).await
+            .with_context(|| format!("Failed to get AppConfig profile {}", spec.description()))?;
+        let prefix = spec.key.as_deref().unwrap_or("");
+
+        if content_type.as_deref().is_some_and(|t| t.starts_with("text/plain")) {
+            if prefix.is_empty() {
+                return Err(anyhow!("AppConfig profile {} is plain text, so needs a key", spec.description()));
+            }
+            data.insert(prefix.to_owned(), String::from_utf8_lossy(&body).into_owned());
+            continue;
+        }
+
+        // JSON is also valid YAML
+        let doc: Value = serde_yaml::from_slice(&body)
+            .with_context(|| format!("Error parsing AppConfig profile {}", spec.description()))?;
+        // Feature flags come as {"<flag>": {"enabled": true, "<attribute>": ...}}
+
+        flatten_value(prefix, &doc, &mut data);
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::model::flatten_value;
+
+const STORAGE_API_VERSION: &str = "2021-08-06";
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+const STORAGE_RESOURCE: &str = "https://storage.azure.com/";
+
+// Everything but unreserved characters and the path separator
+const BLOB_PATH: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~').remove(b'/');
+
+#[derive(Debug, Deserialize)]
+pub struct BlobSpec {
+    /// Storage account name. Required unless a connection string is used.
+    account: Option<String>,
+    container: String,
+    blob: String,
+    /// Environment variable holding a storage connection string. When
+    /// absent, a managed identity token is requested from IMDS instead.
+    connection_string_env: Option<String>,
+    /// Client ID of a user-assigned managed identity.
+    client_id: Option<String>,
+}
+
+enum Auth {
+    SharedKey { account: String, key: Vec<u8> },
+    Sas(String),
+    Bearer(String),
+}
+
+fn parse_connection_string(cs: &str) -> Result<(String, Auth)> {
+    let fields: HashMap<&str, &str> = cs.split(';')
+        .filter_map(|kv| kv.split_once('='))
+        .collect();
+
+    let endpoint = match (fields.get("BlobEndpoint"), fields.get("AccountName")) {
+        (Some(endpoint), _) => endpoint.trim_end_matches('/').to_owned(),
+        (None, Some(account)) => {
+            let protocol = fields.get("DefaultEndpointsProtocol").unwrap_or(&"https");
+            let suffix = fields.get("EndpointSuffix").unwrap_or(&"core.windows.net");
+            format!("{}://{}.blob.{}", protocol, account, suffix)
+        }
+        (None, None) => bail!("Connection string has neither BlobEndpoint nor AccountName")
+    };
+
+    let auth = match (fields.get("SharedAccessSignature"), fields.get("AccountName"), fields.get("AccountKey")) {
+        (Some(sas), _, _) => Auth::Sas(sas.trim_start_matches('?').to_owned()),
+        (None, Some(account), Some(key)) => Auth::SharedKey {
+            account: (*account).to_owned(),
+            key: BASE64.decode(key).context("AccountKey is not valid base64")?,
+        },
+        _ => bail!("Connection string has neither SharedAccessSignature nor AccountName/AccountKey")
+    };
+
+    Ok((endpoint, auth))
+}
+
+async fn managed_identity_token(client: &Client, client_id: Option<&str>) -> Result<String> {
+    let mut params = vec![("api-version", "2018-02-01"), ("resource", STORAGE_RESOURCE)];
+    if let Some(id) = client_id {
+        params.push(("client_id", id));
+    }
+    let url = Url::parse_with_params(IMDS_TOKEN_URL, &params)?;
+
+    let body = client.get(url)
+        .header("Metadata", "true")
+        .send().await?
+        .error_for_status()?
+        .bytes().await?;
+
+    let response: Value = serde_json::from_slice(&body)?;
+    match response.get("access_token") {
+        Some(Value::String(token)) => Ok(token.clone()),
+        _ => bail!("IMDS response did not contain an access token")
+    }
+}
+
+fn shared_key_signature(account: &str, key: &[u8], date: &str, path: &str) -> String {
+    // GET followed by the 11 standard headers, all empty for a simple download
+    let mut string_to_sign = String::from("GET\n");
+    string_to_sign.push_str(&"\n".repeat(11));
+    string_to_sign.push_str(&format!("x-ms-date:{}\nx-ms-version:{}\n/{}{}", date, STORAGE_API_VERSION, account, path));
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(string_to_sign.as_bytes());
+    BASE64.encode(mac.finalize().into_bytes())
+}
+
+async fn fetch_blob(client: &Client, spec: &BlobSpec, tokens: &mut HashMap<Option<String>, String>) -> Result<Vec<u8>> {
+    let (endpoint, auth) = match &spec.connection_string_env {
+        Some(var) => {
+            let cs = std::env::var(var).with_context(|| format!("Environment variable {} not set", var))?;
+            parse_connection_string(&cs)?
+        }
+        None => {
+            let account = spec.account.as_deref()
+                .ok_or_else(|| anyhow!("account is required when not using a connection string"))?;
+            let token = match tokens.get(&spec.client_id) {
+                Some(token) => token.clone(),
+                None => {
+                    let token = managed_identity_token(client, spec.client_id.as_deref()).await
+                        .context("Failed to get managed identity token")?;
+                    tokens.insert(spec.client_id.clone(), token.clone());
+                    token
+                }
+            };
+            (format!("https://{}.blob.core.windows.net", account), Auth::Bearer(token))
+        }
+    };
+
+    let path = format!("/{}/{}", spec.container, utf8_percent_encode(&spec.blob, BLOB_PATH));
+    let date = httpdate::fmt_http_date(SystemTime::now());
+
+    let request = match auth {
+        Auth::SharedKey { account, key } => {
+            let signature = shared_key_signature(&account, &key, &date, &path);
+            client.get(format!("{}{}", endpoint, path))
+                .header("Authorization", format!("SharedKey {}:{}", account, signature))
+        }
+        Auth::Sas(sas) => client.get(format!("{}{}?{}", endpoint, path, sas)),
+        Auth::Bearer(token) => client.get(format!("{}{}", endpoint, path)).bearer_auth(token)
+    };
+
+    let body = request
+        .header("x-ms-date", date)
+        .header("x-ms-version", STORAGE_API_VERSION)
+        .send().await?
+        .error_for_status()?
+        .bytes().await?;
+
+    Ok(body.to_vec())
+}
+
+pub async fn get_azure_blob_properties(blobs: &[BlobSpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    if blobs.is_empty() {
+        return Ok(data);
+    }
+
+    let client = Client::new();
+    let mut tokens = HashMap::new();
+
+    for spec in blobs {
+        let body = fetch_blob(&client, spec, &mut tokens).await
+            .with_context(|| format!("Failed to get blob {}/{}", spec.container, spec.blob))?;
+
+        // JSON is also valid YAML
+        let doc: Value = serde_yaml::from_slice(&body)
+            .with_context(|| format!("Error parsing blob {}/{}", spec.container, spec.blob))?;
+
+        flatten_value("", &doc, &mut data);
+    }
+
+    Ok(data)
+}
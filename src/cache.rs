@@ -0,0 +1,169 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, KeyInit};
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Sha256, Digest};
+
+/// Passphrase the cache key is derived from.
+pub const KEY_ENV: &str = "PSMERGE_CACHE_KEY";
+/// The salt isn't secret, so it's kept with the entries.
+const SALT_FILE: &str = "salt";
+const SALT_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+/// Derives a key from the passphrase with scrypt.
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; KEY_SIZE]> {
+    let mut key = [0u8; KEY_SIZE];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &scrypt::Params::recommended(), &mut key)
+        .map_err(|e| anyhow!("Failed to derive key: {}", e))?;
+    Ok(key)
+}
+
+/// Encrypts with AES-256-GCM, prefixing the random nonce.
+fn encrypt(key: &[u8; KEY_SIZE], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    getrandom::fill(&mut nonce).map_err(|e| anyhow!("Failed to generate nonce: {}", e))?;
+
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let mut sealed = nonce.to_vec();
+    sealed.extend(cipher.encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow!("Encryption failed"))?);
+    Ok(sealed)
+}
+
+fn decrypt(key: &[u8; KEY_SIZE], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_SIZE {
+        return Err(anyhow!("Encrypted data truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    cipher.decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Decryption failed (wrong key?)"))
+}
+
+/// Creates a file only the owner can read.
+pub fn create_private(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Creates a directory (and its parents) only the owner can access.
+pub fn create_private_dir(path: &Path) -> std::io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(path)
+}
+
+/// Fetched properties, kept encrypted on disk for a while so that
+/// repeated runs don't go back to AWS.
+pub struct Cache {
+    dir: PathBuf,
+    ttl: Duration,
+    key: [u8; KEY_SIZE],
+}
+
+/// A job's entry in the cache.
+pub struct Entry<'a> {
+    pub cache: &'a Cache,
+    pub id: String,
+}
+
+impl Cache {
+    /// The key is derived from PSMERGE_CACHE_KEY, which has to be set: a key
+    /// kept next to the entries wouldn't protect them.
+    pub fn open(dir: &Path, ttl: Duration) -> Result<Cache> {
+        let passphrase = std::env::var(KEY_ENV)
+            .map_err(|_| anyhow!("{} must be set to use the cache", KEY_ENV))?;
+
+        create_private_dir(dir)
+            .with_context(|| format!("Error creating cache directory {}", dir.display()))?;
+
+        let salt_path = dir.join(SALT_FILE);
+        let salt = match fs::read(&salt_path) {
+            Ok(salt) => salt,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let mut salt = [0u8; SALT_SIZE];
+                getrandom::fill(&mut salt).map_err(|e| anyhow!("Failed to generate cache salt: {}", e))?;
+                create_private(&salt_path).and_then(|mut f| f.write_all(&salt))
+                    .with_context(|| format!("Error writing cache salt {}", salt_path.display()))?;
+                salt.to_vec()
+            }
+            Err(e) => return Err(e).with_context(|| format!("Error reading cache salt {}", salt_path.display()))
+        };
+        let key = derive_key(&passphrase, &salt)?;
+
+        Ok(Cache { dir: dir.to_owned(), ttl, key })
+    }
+
+    /// Identifies a job by everything that determines what it fetches.
+    pub fn entry(&self, parts: &[&[u8]]) -> Entry<'_> {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        let id = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        Entry { cache: self, id }
+    }
+}
+
+impl Entry<'_> {
+    fn path(&self) -> PathBuf {
+        self.cache.dir.join(&self.id)
+    }
+
+    /// The cached properties, if there are any younger than the TTL.
+    pub fn get<T: DeserializeOwned>(&self, verbosity: u8) -> Option<T> {
+        let path = self.path();
+        let age = fs::metadata(&path).and_then(|m| m.modified()).ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())?;
+        if age > self.cache.ttl {
+            if verbosity > 0 { println!("Cache entry {} expired", path.display()); }
+            return None;
+        }
+
+        let data = fs::read(&path).map_err(anyhow::Error::from)
+            .and_then(|sealed| decrypt(&self.cache.key, &sealed))
+            .and_then(|json| Ok(serde_json::from_slice(&json)?));
+        match data {
+            Ok(data) => {
+                if verbosity > 0 { println!("Using cached properties from {}", path.display()); }
+                Some(data)
+            }
+            Err(e) => {
+                eprintln!("WARNING: Ignoring cache entry {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn put<T: Serialize>(&self, data: &T) -> Result<()> {
+        let path = self.path();
+        let sealed = encrypt(&self.cache.key, &serde_json::to_vec(data)?)?;
+
+        // Readers never see a partial entry
+        let temp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+        create_private(&temp_path).and_then(|mut f| f.write_all(&sealed))
+            .and_then(|_| fs::rename(&temp_path, &path))
+            .with_context(|| format!("Error writing cache entry {}", path.display()))
+    }
+}
@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use aws_config::SdkConfig;
+
+/// Stack outputs, stored as `stackname.OutputKey`.
+pub async fn get_stack_properties(config: &SdkConfig, stacks: &[String]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    if stacks.is_empty() {
+        return Ok(data);
+    }
+
+    let client = aws_sdk_cloudformation::Client::new(config);
+
+    for name in stacks {
+        let response = client.describe_stacks()
+            .stack_name(name)
+            .send().await
+            .with_context(|| format!("Failed to describe stack {}", name))?;
+        let stack = response.stacks.and_then(|s| s.into_iter().next())
+            .ok_or_else(|| anyhow!("Stack {} not found", name))?;
+
+        for output in stack.outputs.unwrap_or_default() {
+            let (key, value) = match (output.output_key, output.output_value) {
+                (Some(key), Some(value)) => (key, value),
+                _ => continue // Incomplete? Skip
+            };
+            data.insert(format!("{}.{}", name, key), value);
+        }
+    }
+
+    Ok(data)
+}
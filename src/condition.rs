@@ -0,0 +1,73 @@
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+use crate::model;
+
+/// Parses the right-hand side of a comparison, quoted (JSON-style) or bare.
+fn operand(s: &str) -> Result<String> {
+    let s = s.trim();
+    if s.starts_with('"') {
+        Ok(serde_json::from_str(s)?)
+    } else if s.is_empty() {
+        bail!("missing value")
+    } else {
+        Ok(s.to_owned())
+    }
+}
+
+fn truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::String(s)) => !(s.is_empty() || s == "false" || s == "0"),
+        Some(Value::Bool(b)) => *b,
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(_) => true,
+    }
+}
+
+/// Splits an expression into its key, whether it's negated & the right-hand
+/// side of its comparison (if any).
+fn parse(expression: &str) -> (&str, bool, Option<&str>) {
+    let (key, negate, comparison) = if let Some((key, rhs)) = expression.split_once("==") {
+        (key, false, Some(rhs))
+    } else if let Some((key, rhs)) = expression.split_once("!=") {
+        (key, true, Some(rhs))
+    } else if let Some(key) = expression.trim().strip_prefix('!') {
+        (key, true, None)
+    } else {
+        (expression, false, None)
+    };
+    (key.trim(), negate, comparison)
+}
+
+/// The model key a `when:` expression tests.
+pub fn key(expression: &str) -> &str {
+    parse(expression).0
+}
+
+/// Evaluates a `when:` expression against the model. Supported are
+/// `key == "value"`, `key != "value"`, `key` (defined and not empty, "false"
+/// or "0") and `!key`.
+pub fn evaluate(expression: &str, model: &Value) -> Result<bool> {
+    let (key, negate, comparison) = parse(expression);
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        bail!("Invalid condition {:?}", expression);
+    }
+    let value = model::lookup(model, key);
+
+    let result = match comparison {
+        Some(rhs) => {
+            let rhs = operand(rhs).map_err(|e| anyhow::anyhow!("Invalid condition {:?}: {}", expression, e))?;
+            match value {
+                Some(Value::String(s)) => *s == rhs,
+                // With coerce_types
+                Some(Value::Bool(b)) => b.to_string() == rhs,
+                Some(Value::Number(n)) => n.to_string() == rhs,
+                _ => false
+            }
+        }
+        None => truthy(value)
+    };
+
+    Ok(result != negate)
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use reqwest::{Client, StatusCode, Url};
+use serde::Deserialize;
+
+const DEFAULT_ADDRESS: &str = "http://127.0.0.1:8500";
+const DEFAULT_TOKEN_ENV: &str = "CONSUL_HTTP_TOKEN";
+
+#[derive(Debug, Deserialize)]
+pub struct ConsulConfig {
+    /// Consul agent address, defaults to $CONSUL_HTTP_ADDR or the local agent.
+    address: Option<String>,
+    /// Environment variable holding the ACL token, if any.
+    token_env: Option<String>,
+    datacenter: Option<String>,
+    /// Key prefixes whose keys (less the prefix, with / becoming .) are
+    /// merged in order.
+    prefixes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KvPair {
+    key: String,
+    /// Base64, null for "folders"
+    value: Option<String>,
+}
+
+async fn get_prefix(client: &Client, config: &ConsulConfig, address: &str, token: Option<&str>, prefix: &str,
+                    data: &mut HashMap<String, String>) -> Result<()> {
+    // Up to a /, so app doesn't also get application/...
+    let prefix = match prefix.trim_matches('/') {
+        "" => String::new(),
+        prefix => format!("{}/", prefix)
+    };
+    let mut url = Url::parse(&format!("{}/v1/kv/{}", address, prefix))?;
+    url.query_pairs_mut().append_pair("recurse", "true");
+    if let Some(dc) = &config.datacenter {
+        url.query_pairs_mut().append_pair("dc", dc);
+    }
+
+    let mut request = client.get(url);
+    if let Some(token) = token {
+        request = request.header("X-Consul-Token", token);
+    }
+    let response = request.send().await?;
+    // No keys under the prefix
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(());
+    }
+    let body = response.error_for_status()?.bytes().await?;
+
+    let pairs: Vec<KvPair> = serde_json::from_slice(&body)?;
+    for pair in pairs {
+        let value = match pair.value {
+            Some(value) => BASE64.decode(value)?,
+            None => continue
+        };
+        let key = pair.key.strip_prefix(prefix.as_str()).unwrap_or(&pair.key).trim_matches('/');
+        if key.is_empty() {
+            continue;
+        }
+        let value = String::from_utf8(value).map_err(|_| anyhow!("Value of {} is not UTF-8", pair.key))?;
+        data.insert(key.replace('/', "."), value);
+    }
+
+    Ok(())
+}
+
+pub async fn get_consul_properties(config: Option<&ConsulConfig>) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    let config = match config {
+        Some(config) => config,
+        None => return Ok(data)
+    };
+
+    let address = config.address.clone()
+        .or_else(|| std::env::var("CONSUL_HTTP_ADDR").ok())
+        .unwrap_or_else(|| DEFAULT_ADDRESS.to_owned());
+    // CONSUL_HTTP_ADDR may omit the scheme
+    let address = if address.contains("://") { address } else { format!("http://{}", address) };
+    let token = match &config.token_env {
+        Some(var) => Some(std::env::var(var).with_context(|| format!("Environment variable {} not set", var))?),
+        None => std::env::var(DEFAULT_TOKEN_ENV).ok()
+    };
+
+    let client = Client::new();
+    for prefix in &config.prefixes {
+        get_prefix(&client, config, address.trim_end_matches('/'), token.as_deref(), prefix, &mut data).await
+            .with_context(|| format!("Failed to get Consul keys {}", prefix))?;
+    }
+
+    Ok(data)
+}
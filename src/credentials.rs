@@ -0,0 +1,32 @@
+use anyhow::{anyhow, bail, Result};
+use aws_config::SdkConfig;
+use aws_credential_types::provider::ProvideCredentials;
+use aws_credential_types::provider::error::CredentialsError;
+use aws_sdk_ssm::error::DisplayErrorContext;
+
+/// Resolves credentials before fetching anything, so that the usual
+/// failures come with a way to fix them rather than as an SDK error chain
+/// from whichever request happened to go first.
+pub async fn check(sdk_config: &SdkConfig, profile: Option<&str>) -> Result<()> {
+    let provider = match sdk_config.credentials_provider() {
+        Some(provider) => provider,
+        None => bail!("No AWS credentials provider configured")
+    };
+
+    let e = match provider.provide_credentials().await {
+        Ok(_) => return Ok(()),
+        Err(e) => e
+    };
+
+    let profile_arg = profile.map(|p| format!(" --profile {}", p)).unwrap_or_default();
+    let detail = DisplayErrorContext(&e).to_string();
+
+    if detail.contains("SSO") || detail.contains("sso") {
+        bail!("AWS SSO session expired or not logged in, run `aws sso login{}`\n\nCaused by: {}", profile_arg, detail);
+    }
+    match e {
+        CredentialsError::CredentialsNotLoaded(_) =>
+            Err(anyhow!("No AWS credentials found. Set them in the environment, choose a profile with --profile, or run `aws configure{}`", profile_arg)),
+        _ => Err(anyhow!("Unable to load AWS credentials: {}", detail))
+    }
+}
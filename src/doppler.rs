@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::keyformat::KeyPrefix;
+
+const DOWNLOAD_URL: &str = "https://api.doppler.com/v3/configs/config/secrets/download";
+const DEFAULT_TOKEN_ENV: &str = "DOPPLER_TOKEN";
+
+/// A Doppler config, its secrets merged in as properties.
+#[derive(Debug, Deserialize)]
+pub struct ConfigSpec {
+    /// Optional with a service token, which is scoped to one config.
+    project: Option<String>,
+    config: Option<String>,
+    /// Environment variable holding the token, defaults to DOPPLER_TOKEN.
+    token_env: Option<String>,
+    /// Prefix for its keys.
+    key: Option<String>,
+}
+
+impl KeyPrefix for ConfigSpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+async fn download(client: &Client, spec: &ConfigSpec) -> Result<Value> {
+    let token_env = spec.token_env.as_deref().unwrap_or(DEFAULT_TOKEN_ENV);
+    let token = std::env::var(token_env)
+        .with_context(|| format!("Environment variable {} not set", token_env))?;
+
+    let mut url = Url::parse(DOWNLOAD_URL)?;
+    url.query_pairs_mut().append_pair("format", "json");
+    if let Some(project) = &spec.project {
+        url.query_pairs_mut().append_pair("project", project);
+    }
+    if let Some(config) = &spec.config {
+        url.query_pairs_mut().append_pair("config", config);
+    }
+
+    let body = client.get(url)
+        .bearer_auth(token)
+        .send().await?
+        .error_for_status()?
+        .bytes().await?;
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+pub async fn get_doppler_properties(specs: &[ConfigSpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    if specs.is_empty() {
+        return Ok(data);
+    }
+
+    let client = Client::new();
+
+    for spec in specs {
+        let description = match (&spec.project, &spec.config) {
+            (Some(project), Some(config)) => format!("{}/{}", project, config),
+            _ => "(service token's config)".to_owned()
+        };
+        let secrets = download(&client, spec).await
+            .with_context(|| format!("Failed to download Doppler secrets {}", description))?;
+        let secrets = match secrets {
+            Value::Object(map) => map,
+            _ => bail!("Doppler secrets {} are not a JSON object", description)
+        };
+
+        for (k, v) in secrets {
+            let v = match v {
+                Value::String(s) => s,
+                other => other.to_string()
+            };
+            let k = match &spec.key {
+                Some(prefix) => format!("{}.{}", prefix, k),
+                None => k
+            };
+            data.insert(k, v);
+        }
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde::Deserialize;
+
+const DEFAULT_KEY_ATTRIBUTE: &str = "key";
+const DEFAULT_VALUE_ATTRIBUTE: &str = "value";
+
+/// Restricts a table to the items with this (string) partition key.
+#[derive(Debug, Deserialize)]
+pub struct PartitionSpec {
+    attribute: String,
+    value: String,
+}
+
+/// A table of key/value items, scanned (or queried, with a partition).
+#[derive(Debug, Deserialize)]
+pub struct TableSpec {
+    table: String,
+    /// Defaults to "key".
+    key_attribute: Option<String>,
+    /// Defaults to "value".
+    value_attribute: Option<String>,
+    partition: Option<PartitionSpec>,
+}
+
+/// Strings, numbers & booleans as strings. Anything else is skipped.
+fn attribute_string(value: &AttributeValue) -> Option<String> {
+    match value {
+        AttributeValue::S(s) | AttributeValue::N(s) => Some(s.clone()),
+        AttributeValue::Bool(b) => Some(b.to_string()),
+        _ => None
+    }
+}
+
+async fn fetch_items(client: &aws_sdk_dynamodb::Client, spec: &TableSpec) -> Result<Vec<HashMap<String, AttributeValue>>> {
+    let pages = match &spec.partition {
+        Some(partition) => client.query()
+            .table_name(&spec.table)
+            .key_condition_expression("#pk = :pk")
+            .expression_attribute_names("#pk", &partition.attribute)
+            .expression_attribute_values(":pk", AttributeValue::S(partition.value.clone()))
+            .into_paginator().send()
+            .try_collect().await?
+            .into_iter().map(|page| page.items.unwrap_or_default())
+            .collect::<Vec<_>>(),
+        None => client.scan()
+            .table_name(&spec.table)
+            .into_paginator().send()
+            .try_collect().await?
+            .into_iter().map(|page| page.items.unwrap_or_default())
+            .collect::<Vec<_>>()
+    };
+    Ok(pages.into_iter().flatten().collect())
+}
+
+pub async fn get_dynamodb_properties(config: &SdkConfig, tables: &[TableSpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    if tables.is_empty() {
+        return Ok(data);
+    }
+
+    let client = aws_sdk_dynamodb::Client::new(config);
+
+    for spec in tables {
+        let key_attribute = spec.key_attribute.as_deref().unwrap_or(DEFAULT_KEY_ATTRIBUTE);
+        let value_attribute = spec.value_attribute.as_deref().unwrap_or(DEFAULT_VALUE_ATTRIBUTE);
+
+        let items = fetch_items(&client, spec).await
+            .with_context(|| format!("Failed to read DynamoDB table {}", spec.table))?;
+
+        for item in items {
+            let key = match item.get(key_attribute).and_then(attribute_string) {
+                Some(key) => key,
+                None => continue // No key? Skip
+            };
+            let value = match item.get(value_attribute).and_then(attribute_string) {
+                Some(value) => value,
+                None => continue // No value? Skip
+            };
+            data.insert(key, value);
+        }
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::keyformat::KeyPrefix;
+use crate::{cache, snapshot};
+
+/// Separates the levels of a variable's key, e.g. PSMERGE_DB__HOST is db.host
+const LEVEL_SEPARATOR: &str = "__";
+/// psmerge's own variables, never merged in (they hold its passphrases)
+const CONTROL_VARS: &[&str] = &[cache::KEY_ENV, snapshot::KEY_ENV];
+
+/// Environment variables, merged in with the highest precedence.
+#[derive(Debug, Deserialize)]
+pub struct EnvSpec {
+    /// Only variables starting with this (which is stripped), e.g. PSMERGE_.
+    /// Required, so PATH, AWS_SECRET_ACCESS_KEY & co. aren't merged in.
+    prefix: String,
+    /// Keep the names as they are, rather than lowercasing them.
+    #[serde(default)]
+    keep_case: bool,
+    /// Prefix for their keys.
+    key: Option<String>,
+}
+
+impl KeyPrefix for EnvSpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+pub fn get_env_properties(spec: Option<&EnvSpec>) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    let spec = match spec {
+        Some(spec) => spec,
+        None => return Ok(data)
+    };
+    let prefix = spec.prefix.as_str();
+    if prefix.is_empty() {
+        bail!("env prefix must not be empty, or the whole environment would be merged in");
+    }
+
+    // Variables that aren't Unicode are skipped
+    for (name, value) in std::env::vars_os().filter_map(|(n, v)| Some((n.into_string().ok()?, v.into_string().ok()?))) {
+        if CONTROL_VARS.contains(&name.as_str()) {
+            continue;
+        }
+        let name = match name.strip_prefix(prefix) {
+            Some(name) if !name.is_empty() => name,
+            _ => continue
+        };
+        let name = if spec.keep_case { name.to_owned() } else { name.to_lowercase() };
+        let k = name.replace(LEVEL_SEPARATOR, ".");
+        let k = match &spec.key {
+            Some(key) => format!("{}.{}", key, k),
+            None => k
+        };
+        data.insert(k, value);
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use reqwest::{Certificate, Client, Identity};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Debug, Deserialize)]
+pub struct EtcdConfig {
+    /// Tried in order until one answers, e.g. https://etcd-0:2379
+    endpoints: Vec<String>,
+    /// CA certificate to verify the servers with (PEM), relative to the
+    /// config file.
+    ca_file: Option<PathBuf>,
+    /// Client certificate & key (PEM), relative to the config file.
+    cert_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    /// Key prefixes whose keys (less the prefix, with / becoming .) are
+    /// merged in order.
+    prefixes: Vec<String>,
+}
+
+fn read_file(config_dir: &Path, path: &Path) -> Result<Vec<u8>> {
+    let path = config_dir.join(path);
+    std::fs::read(&path).with_context(|| format!("Error reading {}", path.display()))
+}
+
+fn client(config_dir: &Path, config: &EtcdConfig) -> Result<Client> {
+    let mut builder = Client::builder();
+    if let Some(ca_file) = &config.ca_file {
+        builder = builder.add_root_certificate(Certificate::from_pem(&read_file(config_dir, ca_file)?)
+            .context("Invalid etcd CA certificate")?);
+    }
+    match (&config.cert_file, &config.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let mut pem = read_file(config_dir, cert_file)?;
+            pem.push(b'\n');
+            pem.extend(read_file(config_dir, key_file)?);
+            builder = builder.identity(Identity::from_pem(&pem).context("Invalid etcd client certificate or key")?);
+        }
+        (None, None) => (),
+        _ => bail!("etcd cert_file & key_file must be given together")
+    }
+    Ok(builder.build()?)
+}
+
+/// The end of the range of keys starting with the prefix.
+fn range_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // All of them
+    vec![0]
+}
+
+async fn range(client: &Client, endpoints: &[String], prefix: &str) -> Result<Value> {
+    let request = json!({
+        "key": BASE64.encode(prefix),
+        "range_end": BASE64.encode(range_end(prefix.as_bytes())),
+    });
+
+    let mut last_error = anyhow!("No etcd endpoints configured");
+    for endpoint in endpoints {
+        let response = client.post(format!("{}/v3/kv/range", endpoint.trim_end_matches('/')))
+            .header("Content-Type", "application/json")
+            .body(request.to_string())
+            .send().await;
+        match response {
+            Ok(response) => {
+                let body = response.error_for_status()?.bytes().await?;
+                return Ok(serde_json::from_slice(&body)?);
+            }
+            // Try the next one
+            Err(e) => last_error = anyhow::Error::from(e).context(format!("Failed to connect to {}", endpoint))
+        }
+    }
+    Err(last_error)
+}
+
+pub async fn get_etcd_properties(config_dir: &Path, config: Option<&EtcdConfig>) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    let config = match config {
+        Some(config) => config,
+        None => return Ok(data)
+    };
+
+    let client = client(config_dir, config)?;
+
+    for prefix in &config.prefixes {
+        // Up to a /, so app doesn't also get application/...
+        let prefix = &match prefix.trim_end_matches('/') {
+            "" => prefix.clone(),
+            trimmed => format!("{}/", trimmed)
+        };
+        let response = range(&client, &config.endpoints, prefix).await
+            .with_context(|| format!("Failed to get etcd keys {}", prefix))?;
+
+        for kv in response.get("kvs").and_then(Value::as_array).into_iter().flatten() {
+            let decode = |field: &str| -> Result<String> {
+                let bytes = BASE64.decode(kv.get(field).and_then(Value::as_str).unwrap_or(""))?;
+                String::from_utf8(bytes).map_err(|_| anyhow!("etcd {} is not UTF-8", field))
+            };
+            let key = decode("key")?;
+            let value = decode("value")?;
+
+            let k = key.strip_prefix(prefix.as_str()).unwrap_or(&key).trim_matches('/');
+            if k.is_empty() {
+                continue;
+            }
+            data.insert(k.replace('/', "."), value);
+        }
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::process::Command;
+
+use crate::keyformat::KeyPrefix;
+use crate::model::flatten_value;
+
+const DEFAULT_TIMEOUT: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    Fail,
+    Warn,
+    Ignore,
+}
+
+/// A command whose standard output (a JSON object) is merged in as
+/// properties.
+#[derive(Debug, Deserialize)]
+pub struct ExecSpec {
+    /// Program & arguments, run (without a shell) in the config file's
+    /// directory.
+    command: Vec<String>,
+    /// In seconds, defaults to 30.
+    timeout: Option<u64>,
+    /// When the command fails, times out or outputs something else than a
+    /// JSON object. Defaults to fail.
+    on_failure: Option<OnFailure>,
+    /// Prefix for its keys.
+    key: Option<String>,
+}
+
+impl KeyPrefix for ExecSpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+async fn run(config_dir: &Path, spec: &ExecSpec) -> Result<Value> {
+    let (program, args) = spec.command.split_first()
+        .ok_or_else(|| anyhow!("Empty command"))?;
+
+    let child = Command::new(program)
+        .args(args)
+        .current_dir(config_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let timeout = Duration::from_secs(spec.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    let output = tokio::time::timeout(timeout, child.wait_with_output()).await
+        .map_err(|_| anyhow!("Timed out after {}s", timeout.as_secs()))??;
+    if !output.status.success() {
+        bail!("Exited with {}", output.status);
+    }
+
+    let doc: Value = serde_json::from_slice(&output.stdout).context("Output is not JSON")?;
+    if !doc.is_object() {
+        bail!("Output is not a JSON object");
+    }
+    Ok(doc)
+}
+
+pub async fn get_exec_properties(config_dir: &Path, specs: &[ExecSpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    for spec in specs {
+        let description = spec.command.join(" ");
+        let doc = match run(config_dir, spec).await {
+            Ok(doc) => doc,
+            Err(e) => match spec.on_failure.unwrap_or(OnFailure::Fail) {
+                OnFailure::Fail => return Err(e).with_context(|| format!("Command {} failed", description)),
+                OnFailure::Warn => {
+                    eprintln!("WARNING: Command {} failed: {:#}", description, e);
+                    continue;
+                }
+                OnFailure::Ignore => continue
+            }
+        };
+
+        flatten_value(spec.key.as_deref().unwrap_or(""), &doc, &mut data);
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::agekeys;
+use crate::keyformat::KeyPrefix;
+use crate::model::flatten_value;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    Ini,
+    Properties,
+    Dotenv,
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// Path that reads standard input instead.
+const STDIN_PATH: &str = "-";
+
+const AGE_EXTENSION: &str = "age";
+
+/// Standard input, read the first time it's needed so every job can use it.
+static STDIN: OnceLock<String> = OnceLock::new();
+
+/// A local file, its properties merged in with the lowest precedence.
+#[derive(Debug, Deserialize)]
+pub struct FileSpec {
+    /// Relative to the config file, or - for standard input.
+    path: PathBuf,
+    /// Inferred from the extension if not given.
+    format: Option<FileFormat>,
+    /// Prefix for its keys.
+    key: Option<String>,
+    /// age identities to decrypt it with, if it's age-encrypted (ends in
+    /// .age). Relative to the config file.
+    age_identities: Option<PathBuf>,
+}
+
+impl KeyPrefix for FileSpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+fn infer_format(path: &Path) -> Result<FileFormat> {
+    // .env has no extension, as far as Path is concerned
+    if path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n == ".env" || n.starts_with(".env.")) {
+        return Ok(FileFormat::Dotenv);
+    }
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("ini") | Some("cfg") | Some("conf") => Ok(FileFormat::Ini),
+        Some("properties") => Ok(FileFormat::Properties),
+        Some("env") => Ok(FileFormat::Dotenv),
+        Some("json") => Ok(FileFormat::Json),
+        Some("yaml") | Some("yml") => Ok(FileFormat::Yaml),
+        Some("toml") => Ok(FileFormat::Toml),
+        _ => Err(anyhow!("Can't tell the format of {}, set format", path.display()))
+    }
+}
+
+/// INI: `[section]` headers prefix the keys that follow (`section.key`),
+/// `;` & `#` start comments and values may be double-quoted.
+fn parse_ini(contents: &str, data: &mut HashMap<String, String>) -> Result<()> {
+    let mut section: Option<String> = None;
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = Some(name.trim().to_owned());
+            continue;
+        }
+
+        let (key, value) = line.split_once(['=', ':'])
+            .ok_or_else(|| anyhow!("line {}: expected key = value", i + 1))?;
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+
+        let key = match &section {
+            Some(section) => format!("{}.{}", section, key.trim()),
+            None => key.trim().to_owned()
+        };
+        data.insert(key, value.to_owned());
+    }
+
+    Ok(())
+}
+
+/// Unescapes a .properties key or value.
+fn properties_unescape(s: &str) -> Result<String> {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('f') => result.push('\u{0c}'),
+            Some('u') => {
+                let hex: String = chars.by_ref().take(4).collect();
+                let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+                    .ok_or_else(|| anyhow!("invalid escape \\u{}", hex))?;
+                result.push(c);
+            }
+            Some(other) => result.push(other),
+            None => ()
+        }
+    }
+
+    Ok(result)
+}
+
+/// Joins continuation lines (ending with an odd number of backslashes).
+fn logical_lines(contents: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+
+    for (i, line) in contents.lines().enumerate() {
+        let (start, mut text) = match current.take() {
+            Some((start, text)) => (start, text + line.trim_start()),
+            None => {
+                let line = line.trim_start();
+                if line.starts_with('#') || line.starts_with('!') {
+                    continue;
+                }
+                (i + 1, line.to_owned())
+            }
+        };
+
+        let backslashes = text.chars().rev().take_while(|c| *c == '\\').count();
+        if backslashes % 2 == 1 {
+            text.pop();
+            current = Some((start, text));
+        } else if !text.is_empty() {
+            lines.push((start, text));
+        }
+    }
+    if let Some(last) = current {
+        lines.push(last);
+    }
+
+    lines
+}
+
+/// Java .properties: `key=value`, `key: value` or `key value`.
+fn parse_properties(contents: &str, data: &mut HashMap<String, String>) -> Result<()> {
+    for (line_no, line) in logical_lines(contents) {
+        // The key ends at the first unescaped separator
+        let mut escaped = false;
+        let end = line.char_indices().find(|&(_, c)| {
+            let found = !escaped && (c == '=' || c == ':' || c.is_whitespace());
+            escaped = !escaped && c == '\\';
+            found
+        }).map(|(i, _)| i).unwrap_or(line.len());
+
+        let (key, rest) = line.split_at(end);
+        let rest = rest.trim_start();
+        let rest = rest.strip_prefix(['=', ':']).unwrap_or(rest).trim_start();
+
+        let key = properties_unescape(key).with_context(|| format!("line {}", line_no))?;
+        let value = properties_unescape(rest).with_context(|| format!("line {}", line_no))?;
+        data.insert(key, value);
+    }
+
+    Ok(())
+}
+
+/// .env: `KEY=value`, optionally after `export`. Values may be single-quoted
+/// (literal) or double-quoted (with \n, \" & co. escapes), and unquoted
+/// ones end at a ` #` comment.
+fn parse_dotenv(contents: &str, data: &mut HashMap<String, String>) -> Result<()> {
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").map(str::trim_start).unwrap_or(line);
+
+        let (key, value) = line.split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected KEY=value", i + 1))?;
+        let value = value.trim();
+
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            quoted.split_once('\'').map(|(v, _)| v.to_owned())
+                .ok_or_else(|| anyhow!("line {}: unterminated quote", i + 1))?
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            let mut result = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => result.push('\n'),
+                        Some('r') => result.push('\r'),
+                        Some('t') => result.push('\t'),
+                        Some(other) => result.push(other),
+                        None => ()
+                    },
+                    Some(c) => result.push(c),
+                    None => bail!("line {}: unterminated quote", i + 1)
+                }
+            }
+            result
+        } else {
+            value.split_once(" #").map(|(v, _)| v.trim_end()).unwrap_or(value).to_owned()
+        };
+
+        data.insert(key.trim().to_owned(), value);
+    }
+
+    Ok(())
+}
+
+/// JSON, YAML & TOML documents are flattened into dotted keys.
+fn parse_document(format: FileFormat, contents: &str, data: &mut HashMap<String, String>) -> Result<()> {
+    let doc: Value = match format {
+        FileFormat::Json => serde_json::from_str(contents)?,
+        FileFormat::Yaml => serde_yaml::from_str(contents)?,
+        FileFormat::Toml => toml::from_str(contents)?,
+        _ => unreachable!()
+    };
+    if !doc.is_object() {
+        bail!("not an object");
+    }
+
+    flatten_value("", &doc, data);
+    Ok(())
+}
+
+pub fn get_file_properties(config_dir: &Path, specs: &[FileSpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    for spec in specs {
+        let (path, format, contents) = if spec.path.as_os_str() == STDIN_PATH {
+            let contents = match STDIN.get() {
+                Some(contents) => contents.clone(),
+                None => {
+                    let mut contents = String::new();
+                    std::io::stdin().read_to_string(&mut contents).context("Error reading standard input")?;
+                    STDIN.get_or_init(|| contents).clone()
+                }
+            };
+            // JSON is also valid YAML
+            (PathBuf::from("standard input"), spec.format.unwrap_or(FileFormat::Yaml), contents)
+        } else {
+            let path = config_dir.join(&spec.path);
+            let encrypted = path.extension().is_some_and(|e| e == AGE_EXTENSION);
+            let format = match spec.format {
+                Some(format) => format,
+                // By the extension beneath .age
+                None if encrypted => infer_format(&path.with_extension(""))?,
+                None => infer_format(&path)?
+            };
+            let contents = if encrypted {
+                let identities = match &spec.age_identities {
+                    Some(identities) => agekeys::load_identities(&config_dir.join(identities))?,
+                    None => bail!("{} is age-encrypted, set age_identities", path.display())
+                };
+                let ciphertext = std::fs::read(&path)
+                    .with_context(|| format!("Error reading {}", path.display()))?;
+                let plaintext = agekeys::decrypt(&identities, &ciphertext)
+                    .with_context(|| format!("Error decrypting {}", path.display()))?;
+                String::from_utf8(plaintext).map_err(|_| anyhow!("{} is not UTF-8", path.display()))?
+            } else {
+                std::fs::read_to_string(&path)
+                    .with_context(|| format!("Error reading {}", path.display()))?
+            };
+            (path, format, contents)
+        };
+
+        let mut file_data = HashMap::new();
+        match format {
+            FileFormat::Ini => parse_ini(&contents, &mut file_data),
+            FileFormat::Properties => parse_properties(&contents, &mut file_data),
+            FileFormat::Dotenv => parse_dotenv(&contents, &mut file_data),
+            FileFormat::Json | FileFormat::Yaml | FileFormat::Toml => parse_document(format, &contents, &mut file_data),
+        }.with_context(|| format!("Error parsing {}", path.display()))?;
+
+        for (k, v) in file_data {
+            let k = match &spec.key {
+                Some(prefix) => format!("{}.{}", prefix, k),
+                None => k
+            };
+            data.insert(k, v);
+        }
+    }
+
+    Ok(data)
+}
+
+/// Reads an overrides file, YAML (or JSON) with flat or nested keys.
+pub fn get_override_properties(path: &Path) -> Result<HashMap<String, String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Error reading {}", path.display()))?;
+
+    let mut data = HashMap::new();
+    // JSON is also valid YAML
+    parse_document(FileFormat::Yaml, &contents, &mut data)
+        .with_context(|| format!("Error parsing {}", path.display()))?;
+    Ok(data)
+}
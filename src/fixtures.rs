@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use aws_smithy_http_client::tls;
+use aws_smithy_http_client::tls::rustls_provider::CryptoMode;
+use aws_smithy_runtime_api::client::http::{HttpClient, HttpConnector, HttpConnectorFuture, HttpConnectorSettings,
+    SharedHttpClient, SharedHttpConnector};
+use aws_smithy_runtime_api::client::orchestrator::{HttpRequest, HttpResponse};
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use aws_smithy_runtime_api::client::runtime_components::RuntimeComponents;
+use aws_smithy_runtime_api::http::StatusCode;
+use aws_smithy_types::body::SdkBody;
+use aws_smithy_types::byte_stream::ByteStream;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Digest};
+
+use crate::cache::{create_private, create_private_dir};
+
+/// Which operation a JSON protocol request is, as its body alone doesn't say.
+const TARGET_HEADER: &str = "x-amz-target";
+
+#[derive(Debug, Clone)]
+pub enum Mode {
+    /// Make the requests & save each response in the directory.
+    Record(PathBuf),
+    /// Answer requests from the responses in the directory instead.
+    Replay(PathBuf),
+}
+
+/// A recorded response, with the request it answers (for reference).
+#[derive(Debug, Serialize, Deserialize)]
+struct Fixture {
+    method: String,
+    uri: String,
+    request: String,
+    status: u16,
+    headers: BTreeMap<String, String>,
+    /// Unless it isn't UTF-8
+    body: Option<String>,
+    body_base64: Option<String>,
+}
+
+/// Fixtures are named after the request, so they can be found again
+/// however many requests are made concurrently & in whatever order.
+fn fixture_path(dir: &Path, request: &HttpRequest) -> PathBuf {
+    let mut hasher = Sha256::new();
+    for part in [request.method().as_bytes(), request.uri().as_bytes(),
+                 request.headers().get(TARGET_HEADER).unwrap_or("").as_bytes(),
+                 request.body().bytes().unwrap_or_default()] {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    let id: String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    dir.join(format!("{}.json", id))
+}
+
+fn replay(path: &Path) -> Result<HttpResponse> {
+    let contents = std::fs::read(path)
+        .with_context(|| format!("No recorded response {}", path.display()))?;
+    let fixture: Fixture = serde_json::from_slice(&contents)
+        .with_context(|| format!("Error parsing recorded response {}", path.display()))?;
+
+    let body = match (fixture.body, fixture.body_base64) {
+        (Some(body), _) => body.into_bytes(),
+        (None, Some(body)) => BASE64.decode(body)?,
+        (None, None) => Vec::new()
+    };
+    let mut response = HttpResponse::new(StatusCode::try_from(fixture.status)?, SdkBody::from(body));
+    for (name, value) in fixture.headers {
+        response.headers_mut().insert(name, value);
+    }
+    Ok(response)
+}
+
+async fn record(path: PathBuf, inner: &SharedHttpConnector, request: HttpRequest) -> Result<HttpResponse> {
+    let method = request.method().to_owned();
+    let uri = request.uri().to_owned();
+    let request_body = String::from_utf8_lossy(request.body().bytes().unwrap_or_default()).into_owned();
+
+    let mut response = inner.call(request).await?;
+    let body = ByteStream::new(response.take_body()).collect().await?.into_bytes();
+    let headers: BTreeMap<String, String> = response.headers().iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+
+    let fixture = Fixture {
+        method,
+        uri,
+        request: request_body,
+        status: response.status().as_u16(),
+        headers,
+        body: std::str::from_utf8(&body).ok().map(str::to_owned),
+        body_base64: std::str::from_utf8(&body).is_err().then(|| BASE64.encode(&body)),
+    };
+    // Responses hold secrets & credentials
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir)
+            .with_context(|| format!("Error creating fixture directory {}", dir.display()))?;
+    }
+    let json = serde_json::to_vec_pretty(&fixture)?;
+    create_private(&path).and_then(|mut f| f.write_all(&json))
+        .with_context(|| format!("Error writing fixture {}", path.display()))?;
+
+    *response.body_mut() = SdkBody::from(body);
+    Ok(response)
+}
+
+#[derive(Debug)]
+struct FixtureConnector {
+    mode: Mode,
+    inner: Option<SharedHttpConnector>,
+}
+
+impl HttpConnector for FixtureConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let mode = self.mode.clone();
+        let inner = self.inner.clone();
+        HttpConnectorFuture::new(async move {
+            let result = match (&mode, &inner) {
+                (Mode::Replay(dir), _) => replay(&fixture_path(dir, &request)),
+                (Mode::Record(dir), Some(inner)) => record(fixture_path(dir, &request), inner, request).await,
+                (Mode::Record(_), None) => Err(anyhow!("Nothing to record from"))
+            };
+            result.map_err(|e| ConnectorError::other(e.into(), None))
+        })
+    }
+}
+
+#[derive(Debug)]
+struct FixtureClient {
+    mode: Mode,
+    inner: SharedHttpClient,
+}
+
+impl HttpClient for FixtureClient {
+    fn http_connector(&self, settings: &HttpConnectorSettings, components: &RuntimeComponents) -> SharedHttpConnector {
+        let inner = match self.mode {
+            Mode::Record(_) => Some(self.inner.http_connector(settings, components)),
+            Mode::Replay(_) => None
+        };
+        SharedHttpConnector::new(FixtureConnector { mode: self.mode.clone(), inner })
+    }
+}
+
+/// HTTP client for the SDK that records or replays responses, going
+/// through `inner` (or the default client) when recording.
+pub fn http_client(mode: &Mode, inner: Option<SharedHttpClient>) -> SharedHttpClient {
+    let inner = inner.unwrap_or_else(|| aws_smithy_http_client::Builder::new()
+        .tls_provider(tls::Provider::Rustls(CryptoMode::AwsLc))
+        .build_https());
+    SharedHttpClient::new(FixtureClient { mode: mode.clone(), inner })
+}
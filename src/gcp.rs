@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::keyformat::KeyPrefix;
+use crate::model::flatten_value;
+
+const SECRET_MANAGER_URL: &str = "https://secretmanager.googleapis.com/v1";
+const METADATA_TOKEN_URL: &str = "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+const DEFAULT_VERSION: &str = "latest";
+
+#[derive(Debug, Deserialize)]
+pub struct SecretSpec {
+    project: String,
+    /// Secret name. Either this or filter is required.
+    secret: Option<String>,
+    /// Or every secret matching this filter, e.g. `labels.app=web`.
+    filter: Option<String>,
+    /// Version to access, defaults to latest.
+    version: Option<String>,
+    /// Property key JSON fields are placed under, or a plain value is
+    /// stored as. Defaults to the top level, or the secret name.
+    key: Option<String>,
+    /// Environment variable holding an access token (e.g. from `gcloud auth
+    /// print-access-token`). When absent, one is requested from the
+    /// metadata server instead.
+    access_token_env: Option<String>,
+}
+
+impl KeyPrefix for SecretSpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+async fn metadata_token(client: &Client) -> Result<String> {
+    let body = client.get(METADATA_TOKEN_URL)
+        .header("Metadata-Flavor", "Google")
+        .send().await?
+        .error_for_status()?
+        .bytes().await?;
+
+    let response: Value = serde_json::from_slice(&body)?;
+    match response.get("access_token") {
+        Some(Value::String(token)) => Ok(token.clone()),
+        _ => bail!("Metadata server response did not contain an access token")
+    }
+}
+
+async fn get_json(client: &Client, url: Url, token: &str) -> Result<Value> {
+    let body = client.get(url)
+        .bearer_auth(token)
+        .send().await?
+        .error_for_status()?
+        .bytes().await?;
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Names of the project's secrets matching the filter, in order.
+async fn list_secrets(client: &Client, token: &str, project: &str, filter: &str) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let mut url = Url::parse(&format!("{}/projects/{}/secrets", SECRET_MANAGER_URL, project))?;
+        url.query_pairs_mut().append_pair("filter", filter);
+        if let Some(page_token) = &page_token {
+            url.query_pairs_mut().append_pair("pageToken", page_token);
+        }
+        let response = get_json(client, url, token).await?;
+
+        for secret in response.get("secrets").and_then(Value::as_array).into_iter().flatten() {
+            // projects/<number>/secrets/<name>
+            if let Some(name) = secret.get("name").and_then(Value::as_str).and_then(|n| n.rsplit('/').next()) {
+                names.push(name.to_owned());
+            }
+        }
+
+        page_token = match response.get("nextPageToken").and_then(Value::as_str) {
+            Some(token) if !token.is_empty() => Some(token.to_owned()),
+            _ => break
+        };
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+async fn access_secret(client: &Client, token: &str, project: &str, secret: &str, version: &str) -> Result<String> {
+    let url = Url::parse(&format!("{}/projects/{}/secrets/{}/versions/{}:access", SECRET_MANAGER_URL, project, secret, version))?;
+    let response = get_json(client, url, token).await?;
+
+    let payload = response.pointer("/payload/data").and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("Response has no payload"))?;
+    String::from_utf8(BASE64.decode(payload)?).context("Secret value is not UTF-8")
+}
+
+fn insert_secret(spec: &SecretSpec, secret: &str, value: String, data: &mut HashMap<String, String>) {
+    match serde_json::from_str::<Value>(&value) {
+        Ok(doc @ Value::Object(_)) => flatten_value(spec.key.as_deref().unwrap_or(""), &doc, data),
+        _ => { data.insert(spec.key.clone().unwrap_or_else(|| secret.to_owned()), value); }
+    }
+}
+
+pub async fn get_gcp_secret_properties(secrets: &[SecretSpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    if secrets.is_empty() {
+        return Ok(data);
+    }
+
+    let client = Client::new();
+    let mut tokens: HashMap<Option<String>, String> = HashMap::new();
+
+    for spec in secrets {
+        let token = match tokens.get(&spec.access_token_env) {
+            Some(token) => token.clone(),
+            None => {
+                let token = match &spec.access_token_env {
+                    Some(var) => std::env::var(var).with_context(|| format!("Environment variable {} not set", var))?,
+                    None => metadata_token(&client).await.context("Failed to get GCP access token from the metadata server")?
+                };
+                tokens.insert(spec.access_token_env.clone(), token.clone());
+                token
+            }
+        };
+
+        let names = match (&spec.secret, &spec.filter) {
+            (Some(secret), _) => vec![secret.clone()],
+            (None, Some(filter)) => list_secrets(&client, &token, &spec.project, filter).await
+                .with_context(|| format!("Failed to list GCP secrets in {}", spec.project))?,
+            (None, None) => bail!("GCP secret in {} needs either secret or filter", spec.project)
+        };
+
+        for name in names {
+            let value = access_secret(&client, &token, &spec.project, &name, spec.version.as_deref().unwrap_or(DEFAULT_VERSION)).await
+                .with_context(|| format!("Failed to access GCP secret {}/{}", spec.project, name))?;
+            insert_secret(spec, &name, value, &mut data);
+        }
+    }
+
+    Ok(data)
+}
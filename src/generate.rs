@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::model::{flatten_value, lookup};
+
+/// Built-in output formats that don't need a template.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// systemd EnvironmentFile of the keys selected by `keys`
+    Envfile,
+    /// Java .properties file of the `root` subtree
+    Properties,
+    /// .env file of the `root` subtree
+    Dotenv,
+    /// The `root` subtree as JSON
+    Json,
+    /// The `root` subtree as YAML
+    Yaml,
+    /// The `root` subtree as TOML
+    Toml,
+    /// The base64 value of `root`, decoded (e.g. a binary secret)
+    Raw,
+}
+
+fn scalar_string(key: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(_) | Value::Bool(_) => Ok(value.to_string()),
+        _ => bail!("Key {} is not a simple value", key)
+    }
+}
+
+/// Double-quotes a value for systemd, escaping the characters it treats specially.
+fn systemd_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '`' | '$') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn generate_envfile(keys: &BTreeMap<String, String>, model: &Value) -> Result<String> {
+    let mut out = String::new();
+    for (name, key) in keys {
+        let value = lookup(model, key).ok_or_else(|| anyhow!("Key {} not defined", key))?;
+        out.push_str(&format!("{}={}\n", name, systemd_quote(&scalar_string(key, value)?)));
+    }
+    Ok(out)
+}
+
+fn subtree<'a>(root: Option<&str>, model: &'a Value) -> Result<&'a Value> {
+    match root {
+        Some(root) => lookup(model, root).ok_or_else(|| anyhow!("Key {} not defined", root)),
+        None => Ok(model)
+    }
+}
+
+/// Flattens the subtree at `root` (or the whole model) into sorted dotted keys.
+fn flatten_subtree(root: Option<&str>, model: &Value) -> Result<BTreeMap<String, String>> {
+    let mut data = HashMap::new();
+    flatten_value("", subtree(root, model)?, &mut data);
+    Ok(data.into_iter().collect())
+}
+
+/// Escapes like java.util.Properties.store(): separators and comment
+/// characters are backslash-escaped, non-ASCII becomes `\uXXXX`.
+fn properties_escape(s: &str, is_key: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for (i, c) in s.chars().enumerate() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\x0c' => escaped.push_str("\\f"),
+            ' ' if is_key || i == 0 => escaped.push_str("\\ "),
+            '=' | ':' | '#' | '!' => { escaped.push('\\'); escaped.push(c); }
+            ' '..='~' => escaped.push(c),
+            _ => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    escaped.push_str(&format!("\\u{:04X}", unit));
+                }
+            }
+        }
+    }
+    escaped
+}
+
+fn generate_properties(root: Option<&str>, model: &Value) -> Result<String> {
+    let mut out = String::new();
+    for (key, value) in flatten_subtree(root, model)? {
+        out.push_str(&format!("{}={}\n", properties_escape(&key, true), properties_escape(&value, false)));
+    }
+    Ok(out)
+}
+
+/// `db.max-conns` becomes `DB__MAX_CONNS`.
+fn dotenv_name(key: &str) -> String {
+    key.split('.')
+        .map(|part| part.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect::<String>())
+        .collect::<Vec<String>>()
+        .join("__")
+}
+
+fn dotenv_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            _ => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn generate_dotenv(root: Option<&str>, model: &Value) -> Result<String> {
+    let mut out = String::new();
+    for (key, value) in flatten_subtree(root, model)? {
+        out.push_str(&format!("{}={}\n", dotenv_name(&key), dotenv_quote(&value)));
+    }
+    Ok(out)
+}
+
+/// The base64 value at `root`, decoded.
+fn generate_raw(root: Option<&str>, model: &Value) -> Result<Vec<u8>> {
+    let key = root.ok_or_else(|| anyhow!("raw format requires root"))?;
+    let value = lookup(model, key).ok_or_else(|| anyhow!("Key {} not defined", key))?;
+    BASE64.decode(scalar_string(key, value)?)
+        .with_context(|| format!("Key {} is not base64", key))
+}
+
+/// Generates output in the given format from the model. `keys` maps output
+/// names to model keys, `root` selects a subtree of the model.
+pub fn generate(format: Format, keys: Option<&BTreeMap<String, String>>, root: Option<&str>, model: &Value) -> Result<Vec<u8>> {
+    let out = match format {
+        Format::Envfile => {
+            let keys = keys.ok_or_else(|| anyhow!("envfile format requires keys"))?;
+            generate_envfile(keys, model)?
+        }
+        Format::Properties => generate_properties(root, model)?,
+        Format::Dotenv => generate_dotenv(root, model)?,
+        // Object keys are kept sorted by serde_json, so output is stable
+        Format::Json => serde_json::to_string_pretty(subtree(root, model)?)? + "\n",
+        Format::Yaml => serde_yaml::to_string(subtree(root, model)?)?,
+        Format::Toml => toml::to_string(subtree(root, model)?)?,
+        Format::Raw => return generate_raw(root, model),
+    };
+    Ok(out.into_bytes())
+}
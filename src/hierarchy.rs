@@ -0,0 +1,53 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Result};
+use aws_config::SdkConfig;
+
+/// Facts available to hierarchy patterns: `region`, `hostname` and any
+/// configured ones (which take precedence).
+pub fn facts(sdk_config: &SdkConfig, configured: Option<&BTreeMap<String, String>>) -> HashMap<String, String> {
+    let mut facts = HashMap::new();
+    if let Some(region) = sdk_config.region() {
+        facts.insert("region".to_owned(), region.to_string());
+    }
+    if let Ok(hostname) = hostname::get() {
+        facts.insert("hostname".to_owned(), hostname.to_string_lossy().into_owned());
+    }
+    facts.extend(configured.into_iter().flatten().map(|(k, v)| (k.clone(), v.clone())));
+    facts
+}
+
+/// Substitutes `{fact}` placeholders, returning None if any fact is undefined.
+fn expand_pattern(pattern: &str, facts: &HashMap<String, String>) -> Result<Option<String>> {
+    let mut result = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => bail!("Unterminated placeholder in hierarchy level {}", pattern)
+        };
+        match facts.get(&rest[start + 1..end]) {
+            Some(value) => result.push_str(value),
+            None => return Ok(None)
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(Some(result))
+}
+
+/// Expands the hierarchy into Parameter Store prefixes, most general first.
+/// Levels referring to undefined facts are skipped.
+pub fn expand(patterns: &[String], facts: &HashMap<String, String>, verbosity: u8) -> Result<Vec<String>> {
+    let mut prefixes = Vec::new();
+    for pattern in patterns {
+        match expand_pattern(pattern, facts)? {
+            Some(prefix) => prefixes.push(prefix),
+            None => if verbosity > 0 { println!("Skipping hierarchy level {} (undefined fact)", pattern); }
+        }
+    }
+    Ok(prefixes)
+}
@@ -0,0 +1,64 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use similar::TextDiff;
+
+use crate::output::{backup_count, backup_path, read_manifest};
+
+struct Generation {
+    path: PathBuf,
+    modified: SystemTime,
+    len: u64,
+    /// When it was replaced, if it's a backup with a manifest entry
+    replaced: Option<SystemTime>,
+}
+
+fn generation(path: &Path, replaced: Option<SystemTime>) -> Result<Option<Generation>> {
+    match std::fs::metadata(path) {
+        Ok(meta) => Ok(Some(Generation { path: path.to_owned(), modified: meta.modified()?, len: meta.len(), replaced })),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Error reading {}", path.display()))
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    let contents = std::fs::read(path).with_context(|| format!("Error reading {}", path.display()))?;
+    Ok(String::from_utf8_lossy(&contents).into_owned())
+}
+
+/// Lists the generations of a rendered file (the file, then its backups),
+/// newest first, followed by the diffs between each of them.
+pub fn history(path: &Path) -> Result<()> {
+    let manifest = read_manifest(path)?;
+    let mut paths = vec![(path.to_owned(), None)];
+    paths.extend((1..=backup_count(path)).map(|g| (backup_path(path, g), manifest.get(g - 1).map(|b| b.backed_up))));
+
+    let generations: Vec<Generation> = paths.into_iter()
+        .map(|(p, replaced)| generation(&p, replaced))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    if generations.is_empty() {
+        bail!("Neither {} nor a backup of it exist", path.display());
+    }
+
+    for (i, g) in generations.iter().enumerate() {
+        let replaced = g.replaced.map(|t| format!("  (replaced {})", httpdate::fmt_http_date(t))).unwrap_or_default();
+        println!("{}  {}  {} bytes  {}{}", i, httpdate::fmt_http_date(g.modified), g.len, g.path.display(), replaced);
+    }
+
+    for pair in generations.windows(2) {
+        let (newer, older) = (&pair[0], &pair[1]);
+        let (old, new) = (read(&older.path)?, read(&newer.path)?);
+
+        println!();
+        print!("{}", TextDiff::from_lines(&old, &new)
+            .unified_diff()
+            .header(&older.path.display().to_string(), &newer.path.display().to_string()));
+    }
+
+    Ok(())
+}
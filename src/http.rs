@@ -0,0 +1,77 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Context, Result};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::keyformat::KeyPrefix;
+use crate::model::flatten_value;
+
+/// A URL whose JSON body is merged in as properties.
+#[derive(Debug, Deserialize)]
+pub struct EndpointSpec {
+    url: String,
+    /// Headers to send as given.
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// Headers to send, with their values from these environment variables.
+    #[serde(default)]
+    header_env: BTreeMap<String, String>,
+    /// Environment variable holding a bearer token.
+    bearer_token_env: Option<String>,
+    /// Prefix for its keys.
+    key: Option<String>,
+}
+
+impl KeyPrefix for EndpointSpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+fn env(var: &str) -> Result<String> {
+    std::env::var(var).with_context(|| format!("Environment variable {} not set", var))
+}
+
+async fn fetch(client: &Client, spec: &EndpointSpec) -> Result<Value> {
+    let mut request = client.get(&spec.url)
+        .header("Accept", "application/json");
+    for (name, value) in &spec.headers {
+        request = request.header(name, value);
+    }
+    for (name, var) in &spec.header_env {
+        request = request.header(name, env(var)?);
+    }
+    if let Some(var) = &spec.bearer_token_env {
+        request = request.bearer_auth(env(var)?);
+    }
+
+    let body = request.send().await?
+        .error_for_status()?
+        .bytes().await?;
+
+    let doc: Value = serde_json::from_slice(&body).context("Response is not JSON")?;
+    if !doc.is_object() {
+        bail!("Response is not a JSON object");
+    }
+    Ok(doc)
+}
+
+pub async fn get_http_properties(endpoints: &[EndpointSpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    if endpoints.is_empty() {
+        return Ok(data);
+    }
+
+    let client = Client::new();
+
+    for spec in endpoints {
+        let doc = fetch(&client, spec).await
+            .with_context(|| format!("Failed to get {}", spec.url))?;
+        flatten_value(spec.key.as_deref().unwrap_or(""), &doc, &mut data);
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+
+const REFERENCE_START: &str = "${";
+/// A literal ${
+const ESCAPED_START: &str = "$${";
+
+/// Resolves `${other.key}` references in the values, recursively.
+pub fn apply(data: &mut HashMap<String, String>) -> Result<()> {
+    let mut keys: Vec<&String> = data.keys().filter(|k| data[*k].contains(REFERENCE_START)).collect();
+    keys.sort();
+
+    let mut resolved = HashMap::new();
+    for key in keys {
+        resolve(key, data, &mut resolved, &mut Vec::new())?;
+    }
+
+    data.extend(resolved);
+    Ok(())
+}
+
+/// `stack` holds the keys being resolved, to catch cycles.
+fn resolve(key: &str, data: &HashMap<String, String>, resolved: &mut HashMap<String, String>, stack: &mut Vec<String>) -> Result<String> {
+    if let Some(value) = resolved.get(key) {
+        return Ok(value.clone());
+    }
+    if let Some(pos) = stack.iter().position(|k| k == key) {
+        bail!("Circular reference {} -> {}", stack[pos..].join(" -> "), key);
+    }
+    let value = &data[key];
+    if !value.contains(REFERENCE_START) {
+        return Ok(value.clone());
+    }
+
+    stack.push(key.to_owned());
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value.as_str();
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix(ESCAPED_START) {
+            result.push_str(REFERENCE_START);
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix(REFERENCE_START) {
+            let end = after.find('}').ok_or_else(|| anyhow!("Unterminated reference in {}", key))?;
+            let name = after[..end].trim();
+            if !data.contains_key(name) {
+                bail!("{} refers to {}, which isn't defined", key, name);
+            }
+            result.push_str(&resolve(name, data, resolved, stack)?);
+            rest = &after[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    stack.pop();
+
+    resolved.insert(key.to_owned(), result.clone());
+    Ok(result)
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use reqwest::{Certificate, Client, Identity, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::keyformat::KeyPrefix;
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const SECRET_SCHEME: &str = "k8s://";
+const CONFIGMAP_SCHEME: &str = "k8s-configmap://";
+
+#[derive(Debug, Deserialize)]
+struct Kubeconfig {
+    #[serde(rename = "current-context")]
+    current_context: String,
+    contexts: Vec<Named<KubeContext>>,
+    clusters: Vec<Named<Cluster>>,
+    users: Vec<Named<User>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Named<T> {
+    name: String,
+    #[serde(alias = "context", alias = "cluster", alias = "user")]
+    value: T,
+}
+
+#[derive(Debug, Deserialize)]
+struct KubeContext {
+    cluster: String,
+    user: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Cluster {
+    server: String,
+    certificate_authority: Option<PathBuf>,
+    certificate_authority_data: Option<String>,
+    #[serde(default)]
+    insecure_skip_tls_verify: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct User {
+    token: Option<String>,
+    client_certificate: Option<PathBuf>,
+    client_certificate_data: Option<String>,
+    client_key: Option<PathBuf>,
+    client_key_data: Option<String>,
+}
+
+fn find<'a, T>(items: &'a [Named<T>], name: &str, what: &str) -> Result<&'a T> {
+    items.iter().find(|n| n.name == name).map(|n| &n.value)
+        .ok_or_else(|| anyhow!("Kubeconfig {} {} not found", what, name))
+}
+
+/// Reads inline (base64) data or the referenced file.
+fn pem_data(data: &Option<String>, path: &Option<PathBuf>) -> Result<Option<Vec<u8>>> {
+    match (data, path) {
+        (Some(data), _) => Ok(Some(BASE64.decode(data)?)),
+        (None, Some(path)) => Ok(Some(std::fs::read(path)
+            .with_context(|| format!("Error reading {}", path.display()))?)),
+        (None, None) => Ok(None)
+    }
+}
+
+pub struct KubeClient {
+    client: Client,
+    server: String,
+    token: Option<String>,
+}
+
+impl KubeClient {
+    /// Uses the in-cluster service account when running in a pod, otherwise
+    /// the current context of $KUBECONFIG (or ~/.kube/config).
+    pub fn new() -> Result<KubeClient> {
+        if let (Ok(host), Ok(port)) = (std::env::var("KUBERNETES_SERVICE_HOST"), std::env::var("KUBERNETES_SERVICE_PORT")) {
+            KubeClient::in_cluster(&host, &port).context("Error configuring in-cluster Kubernetes client")
+        } else {
+            KubeClient::from_kubeconfig().context("Error configuring Kubernetes client from kubeconfig")
+        }
+    }
+
+    fn in_cluster(host: &str, port: &str) -> Result<KubeClient> {
+        let sa_dir = Path::new(SERVICE_ACCOUNT_DIR);
+        let token = std::fs::read_to_string(sa_dir.join("token"))?;
+        let ca = std::fs::read(sa_dir.join("ca.crt"))?;
+
+        let client = Client::builder()
+            .add_root_certificate(Certificate::from_pem(&ca)?)
+            .build()?;
+
+        Ok(KubeClient {
+            client,
+            server: format!("https://{}:{}", host, port),
+            token: Some(token.trim().to_owned()),
+        })
+    }
+
+    fn from_kubeconfig() -> Result<KubeClient> {
+        let path = match std::env::var_os("KUBECONFIG") {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let home = std::env::var_os("HOME").ok_or_else(|| anyhow!("HOME not set"))?;
+                Path::new(&home).join(".kube/config")
+            }
+        };
+        let kubeconfig: Kubeconfig = serde_yaml::from_slice(&std::fs::read(&path)
+            .with_context(|| format!("Error reading {}", path.display()))?)
+            .with_context(|| format!("Error parsing {}", path.display()))?;
+
+        let context = find(&kubeconfig.contexts, &kubeconfig.current_context, "context")?;
+        let cluster = find(&kubeconfig.clusters, &context.cluster, "cluster")?;
+        let user = find(&kubeconfig.users, &context.user, "user")?;
+
+        let mut builder = Client::builder();
+        if let Some(ca) = pem_data(&cluster.certificate_authority_data, &cluster.certificate_authority)? {
+            builder = builder.add_root_certificate(Certificate::from_pem(&ca)?);
+        }
+        if cluster.insecure_skip_tls_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let (Some(mut cert), Some(key)) = (pem_data(&user.client_certificate_data, &user.client_certificate)?,
+                                              pem_data(&user.client_key_data, &user.client_key)?) {
+            cert.extend_from_slice(&key);
+            builder = builder.identity(Identity::from_pem(&cert)?);
+        }
+
+        Ok(KubeClient {
+            client: builder.build()?,
+            server: cluster.server.trim_end_matches('/').to_owned(),
+            token: user.token.clone(),
+        })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, format!("{}{}", self.server, path));
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request
+        }
+    }
+
+    /// Returns the object at the given API path, or None if it doesn't exist.
+    pub async fn get(&self, path: &str) -> Result<Option<Value>> {
+        let response = self.request(reqwest::Method::GET, path).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let body = response.error_for_status()?.bytes().await?;
+        Ok(Some(serde_json::from_slice(&body)?))
+    }
+
+    async fn create(&self, path: &str, object: &Value) -> Result<()> {
+        self.request(reqwest::Method::POST, path)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(object)?)
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn merge_patch(&self, path: &str, patch: &Value) -> Result<()> {
+        self.request(reqwest::Method::PATCH, path)
+            .header("Content-Type", "application/merge-patch+json")
+            .body(serde_json::to_vec(patch)?)
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Secret,
+    ConfigMap,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Secret => "Secret",
+            Kind::ConfigMap => "ConfigMap",
+        }
+    }
+
+    fn resource(self) -> &'static str {
+        match self {
+            Kind::Secret => "secrets",
+            Kind::ConfigMap => "configmaps",
+        }
+    }
+}
+
+/// A single key of a Secret (`k8s://namespace/name/key`) or ConfigMap
+/// (`k8s-configmap://namespace/name/key`).
+#[derive(Debug)]
+pub struct Target {
+    kind: Kind,
+    namespace: String,
+    name: String,
+    key: String,
+}
+
+/// Returns the Kubernetes target if the output path is a k8s URL.
+pub fn parse_target(path: &Path) -> Result<Option<Target>> {
+    let s = match path.to_str() {
+        Some(s) => s,
+        None => return Ok(None)
+    };
+
+    let (kind, rest) = if let Some(rest) = s.strip_prefix(SECRET_SCHEME) {
+        (Kind::Secret, rest)
+    } else if let Some(rest) = s.strip_prefix(CONFIGMAP_SCHEME) {
+        (Kind::ConfigMap, rest)
+    } else {
+        return Ok(None);
+    };
+
+    match rest.split('/').collect::<Vec<&str>>()[..] {
+        [namespace, name, key] if !namespace.is_empty() && !name.is_empty() && !key.is_empty() => Ok(Some(Target {
+            kind,
+            namespace: namespace.to_owned(),
+            name: name.to_owned(),
+            key: key.to_owned(),
+        })),
+        _ => bail!("Invalid Kubernetes output {}, expected namespace/name/key", s)
+    }
+}
+
+/// Returns whether the key was changed.
+pub async fn output(target: &Target, contents: &[u8], verbosity: u8) -> Result<bool> {
+    let description = format!("{} {}/{} key {}", target.kind.name(), target.namespace, target.name, target.key);
+
+    let client = KubeClient::new()?;
+
+    // ConfigMap data must be UTF-8, anything else goes in binaryData
+    let (field, encoded) = match (target.kind, std::str::from_utf8(contents)) {
+        (Kind::ConfigMap, Ok(s)) => ("data", s.to_owned()),
+        (Kind::ConfigMap, Err(_)) => ("binaryData", BASE64.encode(contents)),
+        (Kind::Secret, _) => ("data", BASE64.encode(contents)),
+    };
+
+    let collection = format!("/api/v1/namespaces/{}/{}", target.namespace, target.kind.resource());
+    let path = format!("{}/{}", collection, target.name);
+
+    let existing = client.get(&path).await
+        .with_context(|| format!("Error reading {}", description))?;
+
+    match existing {
+        Some(object) => {
+            // If unchanged, do nothing
+            if object.get(field).and_then(|m| m.get(&target.key)).and_then(Value::as_str) == Some(&encoded) {
+                if verbosity > 0 { println!("{} unchanged", description); }
+                return Ok(false);
+            }
+
+            client.merge_patch(&path, &json!({ field: { &target.key: encoded } })).await
+                .with_context(|| format!("Error updating {}", description))?;
+        }
+        None => {
+            let object = json!({
+                "apiVersion": "v1",
+                "kind": target.kind.name(),
+                "metadata": { "name": target.name, "namespace": target.namespace },
+                field: { &target.key: encoded },
+            });
+            client.create(&collection, &object).await
+                .with_context(|| format!("Error creating {}", description))?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// A Secret or ConfigMap whose data is merged in as properties.
+#[derive(Debug, Deserialize)]
+pub struct SourceSpec {
+    kind: Kind,
+    namespace: String,
+    name: String,
+    /// Prefix for its keys.
+    key: Option<String>,
+}
+
+impl KeyPrefix for SourceSpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+pub async fn get_kubernetes_properties(specs: &[SourceSpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    if specs.is_empty() {
+        return Ok(data);
+    }
+
+    let client = KubeClient::new()?;
+
+    for spec in specs {
+        let description = format!("{} {}/{}", spec.kind.name(), spec.namespace, spec.name);
+        let path = format!("/api/v1/namespaces/{}/{}/{}", spec.namespace, spec.kind.resource(), spec.name);
+        let object = client.get(&path).await
+            .with_context(|| format!("Error reading {}", description))?
+            .ok_or_else(|| anyhow!("{} not found", description))?;
+
+        let fields = object.get("data").and_then(Value::as_object).into_iter().flatten();
+        for (k, v) in fields {
+            let v = v.as_str().unwrap_or_default();
+            // Secret data is base64, ConfigMap data isn't (binaryData is skipped)
+            let v = match spec.kind {
+                Kind::Secret => String::from_utf8(BASE64.decode(v)?)
+                    .map_err(|_| anyhow!("{} key {} is not UTF-8", description, k))?,
+                Kind::ConfigMap => v.to_owned(),
+            };
+            let k = match &spec.key {
+                Some(prefix) => format!("{}.{}", prefix, k),
+                None => k.clone()
+            };
+            data.insert(k, v);
+        }
+    }
+
+    Ok(data)
+}
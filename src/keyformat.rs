@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+/// Levels of the model are always separated by dots after normalizing
+const MODEL_SEPARATOR: &str = ".";
+
+/// How a source's keys are normalized before merging.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyFormat {
+    /// As fetched.
+    #[default]
+    Keep,
+    /// DB_Host is db_host
+    Lowercase,
+    /// DB_HOST is db.host
+    ScreamingSnake,
+    /// db-host is db.host
+    Kebab,
+}
+
+impl KeyFormat {
+    fn transform(self, key: &str) -> String {
+        match self {
+            KeyFormat::Keep => key.to_owned(),
+            KeyFormat::Lowercase => key.to_lowercase(),
+            KeyFormat::ScreamingSnake => key.to_lowercase().replace('_', "."),
+            KeyFormat::Kebab => key.replace('-', "."),
+        }
+    }
+}
+
+/// A setting per source, named as in the config.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PerSource<T> {
+    pub files: T,
+    pub sops: T,
+    pub azure_blobs: T,
+    pub s3_objects: T,
+    pub dynamodb: T,
+    pub cloudformation_stacks: T,
+    pub appconfig: T,
+    /// Prefixes, parameters, filters & the hierarchy.
+    pub parameter_store: T,
+    /// Secrets, secret prefixes & filters.
+    pub secrets_manager: T,
+    pub certificates: T,
+    pub vault: T,
+    pub consul: T,
+    pub etcd: T,
+    pub doppler: T,
+    pub exec: T,
+    pub http: T,
+    pub sql: T,
+    pub kubernetes: T,
+    pub plugins: T,
+    pub gcp_secrets: T,
+    pub accounts: T,
+    pub env: T,
+}
+
+/// A spec which can put its source's keys under a configured key (or
+/// namespace). That part of a key is left as written when normalizing.
+pub trait KeyPrefix {
+    fn key_prefix(&self) -> Option<String>;
+}
+
+/// The key prefixes configured by a source's specs.
+pub fn prefixes<T: KeyPrefix>(specs: Option<&[T]>) -> Vec<String> {
+    specs.unwrap_or_default().iter().filter_map(KeyPrefix::key_prefix).collect()
+}
+
+/// Splits off the longest of the prefixes a key is under (with its dot).
+fn split_prefix<'a>(key: &'a str, prefixes: &[String]) -> (&'a str, &'a str) {
+    prefixes.iter()
+        .filter(|p| key == p.as_str() || key.strip_prefix(p.as_str()).is_some_and(|rest| rest.starts_with(MODEL_SEPARATOR)))
+        .map(String::len)
+        .max()
+        .map(|len| key.split_at((len + 1).min(key.len())))
+        .unwrap_or(("", key))
+}
+
+/// Normalizes a source's keys, its hierarchy separator (if not a dot)
+/// replaced with dots before its format is applied. Only the source's own
+/// part of each key is touched: not the prefixes it was put under, nor
+/// `__meta` & the metadata field names beneath it.
+pub fn apply(format: KeyFormat, separator: Option<&str>, prefixes: &[String], data: HashMap<String, String>) -> HashMap<String, String> {
+    let separator = separator.filter(|s| !s.is_empty() && *s != MODEL_SEPARATOR);
+    if format == KeyFormat::Keep && separator.is_none() {
+        return data;
+    }
+    let normalize = |k: &str| {
+        let k = match separator {
+            Some(separator) => k.replace(separator, MODEL_SEPARATOR),
+            None => k.to_owned()
+        };
+        format.transform(&k)
+    };
+
+    // Sorted, so which value wins when two keys normalize alike is always
+    // the same
+    let mut data: Vec<(String, String)> = data.into_iter().collect();
+    data.sort();
+
+    let mut normalized = HashMap::new();
+    for (k, v) in data {
+        let (prefix, own) = split_prefix(&k, prefixes);
+        let own = match own.strip_prefix(crate::META_KEY).and_then(|rest| rest.strip_prefix(MODEL_SEPARATOR)) {
+            Some(rest) => match rest.rsplit_once(MODEL_SEPARATOR) {
+                Some((key, field)) => format!("{}.{}.{}", crate::META_KEY, normalize(key), field),
+                None => own.to_owned()
+            },
+            None => normalize(own)
+        };
+        let new_key = format!("{}{}", prefix, own);
+        if normalized.insert(new_key.clone(), v).is_some() {
+            eprintln!("WARNING: {} normalized to {}, replacing its existing value", k, new_key);
+        }
+    }
+    normalized
+}
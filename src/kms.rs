@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use aws_config::SdkConfig;
+use aws_sdk_kms::primitives::Blob;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::Deserialize;
+
+const DEFAULT_PREFIX: &str = "kms:";
+
+/// Values holding base64 KMS ciphertext (behind a prefix), to be replaced
+/// by their plaintext.
+#[derive(Debug, Deserialize)]
+pub struct KmsSpec {
+    /// Marks the values to decrypt, defaults to kms:
+    prefix: Option<String>,
+    /// Encryption context the values were encrypted with, if any
+    encryption_context: Option<HashMap<String, String>>,
+}
+
+/// Decrypts the marked values among the (already merged) properties.
+pub async fn decrypt_values(config: &SdkConfig, spec: Option<&KmsSpec>, data: &mut HashMap<String, String>) -> Result<()> {
+    let spec = match spec {
+        Some(spec) => spec,
+        None => return Ok(())
+    };
+    let prefix = spec.prefix.as_deref().unwrap_or(DEFAULT_PREFIX);
+
+    let client = aws_sdk_kms::Client::new(config);
+
+    for (key, value) in data.iter_mut() {
+        let ciphertext = match value.strip_prefix(prefix) {
+            Some(ciphertext) => BASE64.decode(ciphertext.trim())
+                .with_context(|| format!("Property {} is not valid base64 ciphertext", key))?,
+            None => continue
+        };
+
+        let result = client.decrypt()
+            .ciphertext_blob(Blob::new(ciphertext))
+            .set_encryption_context(spec.encryption_context.clone())
+            .send().await.with_context(|| format!("Failed to decrypt property {}", key))?;
+        let plaintext = result.plaintext.map(Blob::into_inner).unwrap_or_default();
+        *value = String::from_utf8(plaintext)
+            .with_context(|| format!("Decrypted property {} is not UTF-8", key))?;
+    }
+
+    Ok(())
+}
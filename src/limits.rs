@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Warn,
+    Truncate,
+    Skip,
+    Fail,
+}
+
+/// Limits on the size of the merged properties, in bytes.
+#[derive(Debug, Deserialize)]
+pub struct LimitsConfig {
+    max_value_size: Option<usize>,
+    /// Of all keys & values together.
+    max_total_size: Option<usize>,
+    /// What to do with values over the limit. Exceeding the total limit
+    /// only warns with `warn`, otherwise it fails.
+    #[serde(default)]
+    action: Action,
+}
+
+/// Largest prefix of `s` no longer than `len` bytes, on a character boundary.
+fn truncated(s: &str, len: usize) -> &str {
+    let mut end = len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+pub fn apply(limits: &LimitsConfig, data: &mut HashMap<String, String>) -> Result<()> {
+    if let Some(max) = limits.max_value_size {
+        let mut oversized: Vec<String> = data.iter()
+            .filter(|(_, v)| v.len() > max)
+            .map(|(k, _)| k.clone())
+            .collect();
+        oversized.sort();
+
+        for key in oversized {
+            let len = data[&key].len();
+            match limits.action {
+                Action::Warn => eprintln!("WARNING: Value of {} is {} bytes, over the limit of {}", key, len, max),
+                Action::Truncate => {
+                    eprintln!("WARNING: Value of {} truncated from {} to {} bytes", key, len, max);
+                    let value = data.get_mut(&key).unwrap();
+                    let end = truncated(value, max).len();
+                    value.truncate(end);
+                }
+                Action::Skip => {
+                    eprintln!("WARNING: Value of {} skipped, {} bytes is over the limit of {}", key, len, max);
+                    data.remove(&key);
+                }
+                Action::Fail => bail!("Value of {} is {} bytes, over the limit of {}", key, len, max),
+            }
+        }
+    }
+
+    if let Some(max) = limits.max_total_size {
+        let total: usize = data.iter().map(|(k, v)| k.len() + v.len()).sum();
+        if total > max {
+            if limits.action != Action::Warn {
+                bail!("Properties total {} bytes, over the limit of {}", total, max);
+            }
+            eprintln!("WARNING: Properties total {} bytes, over the limit of {}", total, max);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use std::fmt;
+
+use handlebars::Handlebars;
+use handlebars::template::{HelperTemplate, Parameter, Template, TemplateElement, TemplateMapping};
+
+// Helpers every Handlebars implementation provides
+const PORTABLE_HELPERS: &[&str] = &["if", "unless", "each", "with", "lookup", "log"];
+
+// Helpers that change the context of their block
+const SCOPING_HELPERS: &[&str] = &["each", "with"];
+
+#[derive(Debug, PartialEq, PartialOrd)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    line: Option<usize>,
+    message: String,
+}
+
+impl Problem {
+    pub fn error(message: String) -> Problem {
+        Problem { severity: Severity::Error, line: None, message }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.line {
+            Some(line) => write!(f, "line {}: {}: {}", line, severity, self.message),
+            None => write!(f, "{}: {}", severity, self.message)
+        }
+    }
+}
+
+/// Whether a dotted key falls within the schema, i.e. is one of the declared
+/// keys or an object containing one. `*` matches any single segment.
+pub fn in_schema(schema: &[String], key: &str) -> bool {
+    let parts: Vec<&str> = key.split(['.', '/']).collect();
+    schema.iter().any(|declared| {
+        let declared: Vec<&str> = declared.split('.').collect();
+        parts.len() <= declared.len()
+            && parts.iter().zip(&declared).all(|(p, d)| *d == "*" || p == d)
+    })
+}
+
+struct Linter<'a, 'reg> {
+    handlebars: &'a Handlebars<'reg>,
+    schema: Option<&'a [String]>,
+    problems: Vec<Problem>,
+    line: Option<usize>,
+    /// Model keys referred to
+    references: Vec<String>,
+    /// Whether it refers to the model as a whole (or to keys it can't tell)
+    whole_model: bool,
+}
+
+impl Linter<'_, '_> {
+    fn report(&mut self, severity: Severity, message: String) {
+        self.problems.push(Problem { severity, line: self.line, message });
+    }
+
+    /// `scoped` is set within blocks that change the context, where
+    /// references are no longer relative to the model root.
+    fn template(&mut self, template: &Template, scoped: bool) {
+        for (i, element) in template.elements.iter().enumerate() {
+            if let Some(TemplateMapping(line, _)) = template.mapping.as_ref().and_then(|m| m.get(i)) {
+                self.line = Some(*line);
+            }
+
+            match element {
+                TemplateElement::RawString(_) | TemplateElement::Comment(_) => (),
+                TemplateElement::HTMLExpression(param) => self.parameter(param, scoped),
+                TemplateElement::Expression(ht) | TemplateElement::HelperBlock(ht) => self.helper(ht, scoped),
+                TemplateElement::DecoratorExpression(_) | TemplateElement::DecoratorBlock(_) =>
+                    self.report(Severity::Warning, "decorators are specific to handlebars-rust".to_owned()),
+                TemplateElement::PartialExpression(_) | TemplateElement::PartialBlock(_) =>
+                    self.report(Severity::Error, "partials are not supported".to_owned()),
+            }
+        }
+    }
+
+    fn helper(&mut self, ht: &HelperTemplate, scoped: bool) {
+        // Plain {{name}} is a reference rather than a helper call
+        if !ht.block && ht.params.is_empty() && ht.hash.is_empty() {
+            self.parameter(&ht.name, scoped);
+            return;
+        }
+
+        let name = ht.name.as_name().unwrap_or("");
+        if self.handlebars.get_helper(name).is_none() {
+            self.report(Severity::Error, format!("unknown helper {}", name));
+        } else if !PORTABLE_HELPERS.contains(&name) {
+            self.report(Severity::Warning, format!("helper {} is specific to handlebars-rust", name));
+        }
+
+        for param in ht.params.iter().chain(ht.hash.values()) {
+            self.parameter(param, scoped);
+        }
+
+        if let Some(template) = &ht.template {
+            self.template(template, scoped || SCOPING_HELPERS.contains(&name));
+        }
+        if let Some(inverse) = &ht.inverse {
+            self.template(inverse, scoped);
+        }
+    }
+
+    fn parameter(&mut self, param: &Parameter, scoped: bool) {
+        match param {
+            Parameter::Subexpression(se) => {
+                if let TemplateElement::Expression(ht) = se.as_element() {
+                    self.helper(ht, scoped);
+                }
+            }
+            Parameter::Name(_) | Parameter::Path(_) => {
+                let name = param.as_name().unwrap_or("");
+                if let Some(key) = name.strip_prefix("@root.").or_else(|| name.strip_prefix("@root/")) {
+                    self.references.push(key.to_owned());
+                } else if name == "@root" || name.starts_with("..") || (name == "this" && !scoped) {
+                    self.whole_model = true;
+                }
+                if scoped {
+                    return;
+                }
+                let key = name.strip_prefix("this.").or_else(|| name.strip_prefix("this/")).unwrap_or(name);
+                // Locals (@index, etc.), parents & the context itself aren't model keys
+                if key.starts_with('@') || key.starts_with("..") || key == "this" {
+                    return;
+                }
+                self.references.push(key.to_owned());
+                if let Some(schema) = self.schema {
+                    if !in_schema(schema, key) {
+                        self.report(Severity::Error, format!("reference {} is not in the key schema", key));
+                    }
+                }
+            }
+            _ => ()
+        }
+    }
+}
+
+/// Statically checks a template: syntax (including unbalanced blocks),
+/// helpers, references against the key schema (if any) and portability.
+pub fn lint_template(handlebars: &Handlebars, source: &str, schema: Option<&[String]>) -> Vec<Problem> {
+    let template = match Template::compile2(source, true) {
+        Ok(template) => template,
+        Err(e) => return vec![Problem { line: e.line_no, ..Problem::error(e.reason.to_string()) }]
+    };
+
+    let mut linter = Linter { handlebars, schema, problems: Vec::new(), line: None, references: Vec::new(), whole_model: false };
+    linter.template(&template, false);
+    linter.problems
+}
+
+/// The model keys a template refers to (dotted, as in the properties), or
+/// None if it refers to the model as a whole. Keys used within `each` &
+/// `with` blocks are covered by the key the block is over.
+pub fn references(handlebars: &Handlebars, source: &str) -> Option<Vec<String>> {
+    let template = Template::compile2(source, true).ok()?;
+
+    let mut linter = Linter { handlebars, schema: None, problems: Vec::new(), line: None, references: Vec::new(), whole_model: false };
+    linter.template(&template, false);
+    if linter.whole_model {
+        return None;
+    }
+    Some(linter.references.iter()
+        .map(|key| key.split(['.', '/']).map(|part| part.trim_start_matches('[').trim_end_matches(']')).collect::<Vec<_>>().join("."))
+        .collect())
+}
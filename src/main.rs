@@ -1,25 +1,148 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet, hash_map::Entry};
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::fs::File;
+use std::io::Write;
+use std::time::{Duration, SystemTime};
 
 use aws_config::{Region, SdkConfig};
+use aws_credential_types::Credentials;
+use aws_credential_types::provider::SharedCredentialsProvider;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use aws_sdk_secretsmanager::types::{Filter, FilterNameStringType, SecretListEntry};
 use aws_sdk_secretsmanager::types::error::ResourceNotFoundException;
-use clap::Parser;
-use serde::Deserialize;
+use aws_sdk_ssm::error::ProvideErrorMetadata;
+use aws_sdk_ssm::primitives::DateTimeFormat;
+use aws_sdk_ssm::types::{Parameter, ParameterStringFilter, ParameterType};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use handlebars::{Handlebars, no_escape};
-use anyhow::{Context, Result};
-use tokio::{join, runtime::Runtime};
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{stream, FutureExt, StreamExt, TryStreamExt};
+use futures::future::LocalBoxFuture;
+use tokio::{join, runtime::Runtime, time::Instant};
 
+use crate::keyformat::KeyPrefix;
+use crate::source::Source;
+
+mod accounts;
+mod acm;
+mod agekeys;
+mod appconfig;
+mod azure;
+mod cache;
+mod cloudformation;
+mod condition;
+mod consul;
+mod credentials;
+mod doppler;
+mod dynamodb;
+mod env;
+mod etcd;
+mod exec;
+mod files;
+mod fixtures;
+mod gcp;
+mod generate;
+mod hierarchy;
+mod history;
+mod http;
+mod interpolate;
+mod k8s;
+mod keyformat;
+mod kms;
+mod limits;
+mod lint;
+mod metrics;
 mod model;
+mod notify;
+mod order;
 mod output;
+mod privileges;
+mod proxy;
+mod push;
+mod rds;
+mod rename;
+mod retry;
+mod s3;
+mod schema;
+mod snapshot;
+mod source;
+mod sql;
+mod sops;
+mod timeouts;
+mod vault;
+mod watch;
+
+const ROTATION_WAIT_ATTEMPTS: u32 = 5;
+const ROTATION_WAIT_DELAY: Duration = Duration::from_secs(2);
+const PENDING_SUFFIX: &str = "_pending";
+const GET_PARAMETERS_BATCH_SIZE: usize = 10;
+const DESCRIBE_PARAMETERS_BATCH_SIZE: usize = 50;
+const LIST_SECRETS_BATCH_SIZE: usize = 10;
+const DEFAULT_FETCH_CONCURRENCY: usize = 4;
+const DEFAULT_BACKUPS: usize = 1;
+const META_KEY: &str = "__meta";
+/// Where parameters fetched by name are kept when watching
+const PARAMETERS_WATCH_KEY: &str = "parameters";
+
+const TESTS_DIR: &str = "tests";
+const TEST_MODEL: &str = "model.yaml";
+
+// Same as timeout(1)
+const EXIT_TIMEOUT: i32 = 124;
+/// How long notifications & metrics may take, even after --timeout expires
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_CACHE_TTL: u64 = 300;
+/// Environment variables the AWS config is loaded from, which select the
+/// account, region & endpoints fetched from
+const CACHE_KEY_ENV: &[&str] = &["AWS_PROFILE", "AWS_DEFAULT_PROFILE", "AWS_REGION", "AWS_DEFAULT_REGION",
+    "AWS_ACCESS_KEY_ID", "AWS_ROLE_ARN", "AWS_ENDPOINT_URL", "AWS_CONFIG_FILE", "AWS_SHARED_CREDENTIALS_FILE"];
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Opt {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// AWS region.
     #[clap(long)]
     region: Option<String>,
 
+    /// Named AWS profile.
+    #[clap(long)]
+    profile: Option<String>,
+
+    /// Prefixes & secrets to fetch at once.
+    #[clap(long)]
+    fetch_concurrency: Option<usize>,
+
+    /// Endpoint URL for Parameter Store & Secrets Manager.
+    #[clap(long)]
+    endpoint_url: Option<String>,
+
+    /// Role to assume.
+    #[clap(long)]
+    role_arn: Option<String>,
+
+    /// External ID for the role.
+    #[clap(long)]
+    external_id: Option<String>,
+
+    /// Session name for the role.
+    #[clap(long)]
+    session_name: Option<String>,
+
+    /// MFA device (serial number or ARN) the role requires.
+    #[clap(long)]
+    mfa_serial: Option<String>,
+
+    /// MFA code, rather than prompting for it.
+    #[clap(long)]
+    mfa_token: Option<String>,
+
     /// Increase verbosity.
     #[clap(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
@@ -32,230 +155,2364 @@ struct Opt {
     #[clap(short='B', long="no-backup")]
     nobackup: bool,
 
+    /// Carry on with the other templates when one fails, reporting all
+    /// the errors at the end.
+    #[clap(short='k', long)]
+    keep_going: bool,
+
+    /// Re-read written files and check they match what was rendered.
+    #[clap(long)]
+    verify: bool,
+
+    /// Write file outputs beneath this directory instead of /, e.g. when
+    /// building images.
+    #[clap(long)]
+    prefix: Option<PathBuf>,
+
+    /// Only run the named job(s) of a multi-document config.
+    #[clap(long = "job", value_name = "NAME")]
+    jobs: Vec<String>,
+
+    /// Set a fact for the hierarchy, overriding the config file.
+    #[clap(long = "fact", value_name = "NAME=VALUE", value_parser = parse_fact)]
+    facts: Vec<(String, String)>,
+
+    /// Give up after this many seconds, exiting with status 124. Nothing is
+    /// written if it expires while fetching.
+    #[clap(long)]
+    timeout: Option<u64>,
+
+    /// Fail on missing secrets & parameters, unless marked `required: false`.
+    #[clap(long)]
+    strict_sources: bool,
+
+    /// What to do when sources define a key differently, overriding the
+    /// config file.
+    #[clap(long, value_enum, value_name = "ACTION")]
+    on_conflict: Option<OnConflict>,
+
+    /// YAML file of values that override everything fetched.
+    #[clap(long, value_name = "PATH")]
+    override_file: Option<PathBuf>,
+
+    /// Print which source each key came from (without the values).
+    #[clap(long)]
+    explain: bool,
+
+    /// Cache fetched properties (encrypted) in this directory.
+    #[clap(long)]
+    cache: Option<PathBuf>,
+
+    /// How long cached properties are used for, in seconds.
+    #[clap(long, default_value_t = DEFAULT_CACHE_TTL)]
+    cache_ttl: u64,
+
+    /// Render from a snapshot instead of fetching anything.
+    #[clap(long, value_name = "SNAPSHOT")]
+    offline: Option<PathBuf>,
+
+    /// Save the responses to AWS requests in this directory.
+    #[clap(long, value_name = "DIR", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Answer AWS requests from responses saved with --record.
+    #[clap(long, value_name = "DIR")]
+    replay: Option<PathBuf>,
+
+    /// Keep running, fetching again every this many seconds & only
+    /// re-rendering the templates whose keys changed.
+    #[clap(long, value_name = "SECONDS", conflicts_with = "offline")]
+    watch: Option<u64>,
+
     /// Configuration file
-    config: PathBuf,
+    #[clap(required = true)]
+    config: Option<PathBuf>,
+}
+
+fn parse_fact(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_owned(), value.to_owned())),
+        _ => Err("expected NAME=VALUE".to_owned())
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print a shell completion script to stdout.
+    Completions {
+        shell: clap_complete::Shell,
+    },
+    /// Statically check the templates of a configuration.
+    Lint {
+        /// Configuration file
+        config: PathBuf,
+    },
+    /// Render the templates against the fixtures under tests/ (next to the
+    /// configuration) & compare with the expected outputs.
+    Test {
+        /// Configuration file
+        config: PathBuf,
+    },
+    /// List the previous generations of a rendered file & diff them.
+    History {
+        /// Output file
+        path: PathBuf,
+    },
+    /// Put back a rendered file's backup, with its recorded mode,
+    /// timestamps & ownership.
+    Restore {
+        /// Which backup, 1 being the latest.
+        #[clap(long, default_value_t = 1)]
+        generation: usize,
+        /// Output file
+        path: PathBuf,
+    },
+    /// Fetch everything & save it, for rendering later with --offline.
+    Snapshot {
+        /// Encrypt it with a key derived from PSMERGE_SNAPSHOT_KEY.
+        #[clap(long)]
+        encrypt: bool,
+        /// Configuration file
+        config: PathBuf,
+        /// Snapshot file to write
+        out: PathBuf,
+    },
 }
 
 #[derive(Debug, Deserialize)]
 struct TemplateSpec {
-    src: PathBuf,
+    src: Option<PathBuf>,
     out: PathBuf,
+    /// Generate output in a built-in format instead of rendering src
+    format: Option<generate::Format>,
+    /// Output names to model keys, for formats that take them
+    keys: Option<BTreeMap<String, String>>,
+    /// Model subtree, for formats that dump one
+    root: Option<String>,
+    http: Option<push::HttpSpec>,
+    /// Only render when this condition on the model holds
+    when: Option<String>,
+    /// Referred to by `after`, defaults to out
+    name: Option<String>,
+    /// Templates that must be written before this one
+    after: Option<Vec<String>>,
+}
+
+impl TemplateSpec {
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.out.display().to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Rotation {
+    /// Wait for an in-progress rotation to finish before fetching
+    Wait,
+    /// Also fetch AWSPENDING, exposing its keys with a `_pending` suffix
+    Pending,
+}
+
+// Only lives until it's converted to a SecretSpec
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SecretEntry {
+    Id(String),
+    Spec {
+        id: String,
+        rotation: Option<Rotation>,
+        key: Option<String>,
+        binary_key: Option<String>,
+        version_stage: Option<String>,
+        version_id: Option<String>,
+        region: Option<String>,
+        role_arn: Option<String>,
+        external_id: Option<String>,
+        session_name: Option<String>,
+        required: Option<bool>,
+        namespace: Option<String>,
+    },
+}
+
+/// A Secrets Manager secret, given as just its ID or as an object.
+#[derive(Debug, Deserialize)]
+#[serde(from = "SecretEntry")]
+struct SecretSpec {
+    id: String,
+    rotation: Option<Rotation>,
+    /// Store the secret string as-is under this key, rather than as JSON
+    key: Option<String>,
+    /// Where a binary secret's value is stored (as base64), defaults to key
+    /// or the ID
+    binary_key: Option<String>,
+    /// Fetch this stage (e.g. AWSPREVIOUS) instead of AWSCURRENT
+    version_stage: Option<String>,
+    /// Or pin this specific version
+    version_id: Option<String>,
+    /// Fetch from this region instead
+    region: Option<String>,
+    /// Role to assume to fetch it, e.g. in another account
+    role_arn: Option<String>,
+    external_id: Option<String>,
+    session_name: Option<String>,
+    /// Fail if it doesn't exist, rather than skipping it (the default
+    /// unless --strict-sources)
+    required: Option<bool>,
+    /// Model subtree its keys go under, e.g. db
+    namespace: Option<String>,
+}
+
+impl From<SecretEntry> for SecretSpec {
+    fn from(entry: SecretEntry) -> SecretSpec {
+        match entry {
+            SecretEntry::Id(id) => SecretSpec { id, rotation: None, key: None, binary_key: None, version_stage: None, version_id: None, region: None, role_arn: None, external_id: None, session_name: None, required: None, namespace: None },
+            SecretEntry::Spec { id, rotation, key, binary_key, version_stage, version_id, region, role_arn, external_id, session_name, required, namespace } =>
+                SecretSpec { id, rotation, key, binary_key, version_stage, version_id, region, role_arn, external_id, session_name, required, namespace },
+        }
+    }
+}
+
+impl KeyPrefix for SecretSpec {
+    fn key_prefix(&self) -> Option<String> {
+        match (&self.namespace, self.key.as_ref().or(self.binary_key.as_ref())) {
+            (Some(namespace), Some(key)) => Some(format!("{}.{}", namespace, key)),
+            (namespace, key) => namespace.as_ref().or(key).cloned()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SecureStrings {
+    Include,
+    Exclude,
+    Only,
+}
+
+/// What to do when a prefix has no parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OnEmpty {
+    Ignore,
+    Warn,
+    Error,
+}
+
+/// What to do when sources define a key differently.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum OnConflict {
+    Error,
+    Warn,
+    /// The later source takes precedence
+    #[default]
+    LastWins,
+    FirstWins,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PrefixEntry {
+    Path(String),
+    Spec {
+        path: String,
+        secure_strings: Option<SecureStrings>,
+        decrypt: Option<bool>,
+        recursive: Option<bool>,
+        split_string_lists: Option<bool>,
+        label: Option<String>,
+        nested: Option<bool>,
+        metadata: Option<bool>,
+        max_results: Option<i32>,
+        page_delay_ms: Option<u64>,
+        region: Option<String>,
+        role_arn: Option<String>,
+        external_id: Option<String>,
+        session_name: Option<String>,
+        on_empty: Option<OnEmpty>,
+        namespace: Option<String>,
+        parse_json: Option<bool>,
+    },
+}
+
+/// A Parameter Store prefix, given as just its path or as an object.
+#[derive(Debug, Deserialize)]
+#[serde(from = "PrefixEntry")]
+struct PrefixSpec {
+    path: String,
+    secure_strings: SecureStrings,
+    decrypt: bool,
+    /// Also fetch beneath direct children
+    recursive: bool,
+    /// Turn the remaining path segments into nested objects (db/host
+    /// becomes db.host), rather than keeping them in one key
+    nested: bool,
+    /// Also store each parameter's metadata under __meta
+    metadata: bool,
+    /// Store StringList items under their index (key.0, key.1, ...) so
+    /// they can be iterated
+    split_string_lists: bool,
+    /// Only fetch parameters with this label, at the labelled version
+    label: Option<String>,
+    /// Page size (at most 10)
+    max_results: Option<i32>,
+    /// Pause between pages, to stay within the account's request quota
+    page_delay_ms: Option<u64>,
+    /// Fetch from this region instead
+    region: Option<String>,
+    /// Role to assume to fetch it, e.g. in another account
+    role_arn: Option<String>,
+    external_id: Option<String>,
+    session_name: Option<String>,
+    on_empty: OnEmpty,
+    /// Model subtree its keys go under, e.g. db
+    namespace: Option<String>,
+    /// Flatten values that are JSON objects or arrays beneath their keys
+    parse_json: bool,
+}
+
+impl From<PrefixEntry> for PrefixSpec {
+    fn from(entry: PrefixEntry) -> PrefixSpec {
+        match entry {
+            PrefixEntry::Path(path) => PrefixSpec {
+                path,
+                secure_strings: SecureStrings::Include,
+                decrypt: true,
+                recursive: false,
+                split_string_lists: false,
+                label: None,
+                nested: false,
+                metadata: false,
+                max_results: None,
+                page_delay_ms: None,
+                region: None,
+                role_arn: None,
+                external_id: None,
+                session_name: None,
+                on_empty: OnEmpty::Ignore,
+                namespace: None,
+                parse_json: false,
+            },
+            PrefixEntry::Spec { path, secure_strings, decrypt, recursive, split_string_lists, label, nested, metadata, max_results, page_delay_ms, region, role_arn, external_id, session_name, on_empty, namespace, parse_json } => PrefixSpec {
+                path,
+                secure_strings: secure_strings.unwrap_or(SecureStrings::Include),
+                decrypt: decrypt.unwrap_or(true),
+                recursive: recursive.unwrap_or(false),
+                split_string_lists: split_string_lists.unwrap_or(false),
+                label,
+                nested: nested.unwrap_or(false),
+                metadata: metadata.unwrap_or(false),
+                max_results,
+                page_delay_ms,
+                region,
+                role_arn,
+                external_id,
+                session_name,
+                on_empty: on_empty.unwrap_or(OnEmpty::Ignore),
+                namespace,
+                parse_json: parse_json.unwrap_or(false),
+            },
+        }
+    }
+}
+
+impl KeyPrefix for PrefixSpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.namespace.clone()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ParameterEntry {
+    Name(String),
+    Spec {
+        name: String,
+        key: Option<String>,
+        label: Option<String>,
+        version: Option<u64>,
+        metadata: Option<bool>,
+        required: Option<bool>,
+        parse_json: Option<bool>,
+    },
+}
+
+/// A single parameter, given as just its name or as an object.
+#[derive(Debug, Deserialize)]
+#[serde(from = "ParameterEntry")]
+struct ParameterSpec {
+    name: String,
+    /// Defaults to the last segment of the name
+    key: String,
+    /// name:label or name:version, if either is given
+    selector: Option<String>,
+    /// Also store its metadata under __meta
+    metadata: bool,
+    /// Fail if it doesn't exist, rather than warning (the default unless
+    /// --strict-sources), or skip it quietly if false
+    required: Option<bool>,
+    /// Flatten its value beneath key if it's a JSON object or array
+    parse_json: bool,
+}
+
+impl From<ParameterEntry> for ParameterSpec {
+    fn from(entry: ParameterEntry) -> ParameterSpec {
+        let (name, key, selector, metadata, required, parse_json) = match entry {
+            ParameterEntry::Name(name) => (name, None, None, None, None, None),
+            ParameterEntry::Spec { name, key, label: Some(label), metadata, required, parse_json, .. } => (name, key, Some(label), metadata, required, parse_json),
+            ParameterEntry::Spec { name, key, version, metadata, required, parse_json, .. } => (name, key, version.map(|v| v.to_string()), metadata, required, parse_json),
+        };
+        let key = key.unwrap_or_else(|| name.rsplit('/').next().unwrap_or(&name).to_owned());
+        let selector = selector.map(|s| format!("{}:{}", name, s));
+        ParameterSpec { name, key, selector, metadata: metadata.unwrap_or(false), required, parse_json: parse_json.unwrap_or(false) }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct Config {
+    /// Job name, for selecting with --job
+    name: Option<String>,
     region: Option<String>,
-    parameter_store_prefixes: Option<Vec<String>>,
-    secrets: Option<Vec<String>>,
+    /// Named AWS profile to load credentials & settings from
+    profile: Option<String>,
+    /// Role to assume for this job
+    role_arn: Option<String>,
+    external_id: Option<String>,
+    /// Defaults to psmerge
+    session_name: Option<String>,
+    /// MFA device the role requires, prompted for its code
+    mfa_serial: Option<String>,
+    /// Proxy for AWS requests, instead of HTTPS_PROXY & co.
+    proxy: Option<proxy::ProxySpec>,
+    /// Endpoint URL overrides, e.g. for interface endpoints
+    endpoints: Option<EndpointsSpec>,
+    /// Use FIPS endpoints, e.g. in GovCloud
+    use_fips: Option<bool>,
+    /// Use dual-stack (IPv4 & IPv6) endpoints
+    use_dual_stack: Option<bool>,
+    /// Retry policy for AWS requests
+    retry: Option<retry::RetrySpec>,
+    /// Connect, read & overall timeouts for AWS requests
+    timeouts: Option<timeouts::TimeoutSpec>,
+    /// Prefixes & secrets to fetch at once
+    fetch_concurrency: Option<usize>,
+    /// What to do when sources define a key differently, defaults to
+    /// last-wins
+    on_conflict: Option<OnConflict>,
+    parameter_store_prefixes: Option<Vec<PrefixSpec>>,
+    parameters: Option<Vec<ParameterSpec>>,
+    /// Parameters to discover by DescribeParameters filters (all of them)
+    parameter_filters: Option<Vec<ParameterFilterSpec>>,
+    /// Parameter Store prefix patterns, expanded with facts
+    hierarchy: Option<Vec<String>>,
+    facts: Option<BTreeMap<String, String>>,
+    secrets: Option<Vec<SecretSpec>>,
+    /// Secrets to discover by name prefix
+    secret_prefixes: Option<Vec<String>>,
+    /// Or by ListSecrets filters (all of them)
+    secret_filters: Option<Vec<SecretFilterSpec>>,
+    files: Option<Vec<files::FileSpec>>,
+    /// sops-encrypted files, decrypted with KMS or age
+    sops: Option<Vec<sops::SopsSpec>>,
+    /// Environment variables, e.g. for local development
+    env: Option<env::EnvSpec>,
+    azure_blobs: Option<Vec<azure::BlobSpec>>,
+    appconfig: Option<Vec<appconfig::ProfileSpec>>,
+    /// s3://bucket/key URIs of JSON or YAML documents
+    s3_objects: Option<Vec<String>>,
+    dynamodb: Option<Vec<dynamodb::TableSpec>>,
+    /// Stacks whose outputs are stored as stackname.OutputKey
+    cloudformation_stacks: Option<Vec<String>>,
+    certificates: Option<Vec<acm::CertificateSpec>>,
+    vault: Option<vault::VaultConfig>,
+    consul: Option<consul::ConsulConfig>,
+    etcd: Option<etcd::EtcdConfig>,
+    doppler: Option<Vec<doppler::ConfigSpec>>,
+    /// Commands outputting JSON, for stores without a source of their own
+    exec: Option<Vec<exec::ExecSpec>>,
+    /// URLs serving JSON, e.g. a feature flag service
+    http: Option<Vec<http::EndpointSpec>>,
+    /// Queries of settings tables
+    sql: Option<Vec<sql::QuerySpec>>,
+    /// Secrets & ConfigMaps in the cluster
+    kubernetes: Option<Vec<k8s::SourceSpec>>,
+    /// Sources provided by psmerge-source-<name> programs
+    plugins: Option<Vec<source::PluginSpec>>,
+    /// Google Cloud Secret Manager secrets
+    gcp_secrets: Option<Vec<gcp::SecretSpec>>,
+    rds_iam_tokens: Option<Vec<rds::TokenSpec>>,
+    /// Decrypt values that are KMS ciphertext, e.g. kms:AQICAHh...
+    kms: Option<kms::KmsSpec>,
+    /// YAML file (relative to the config file) of values with the highest
+    /// precedence, e.g. local patches. Overridden by --override-file.
+    override_file: Option<PathBuf>,
+    /// Lowest precedence values, for keys no source defines (nested or dotted)
+    defaults: Option<Value>,
+    /// Resolve ${other.key} references in values after merging
+    interpolate: Option<bool>,
+    /// Keys to rename after fetching, exactly or by /regex/
+    rename: Option<rename::RenameRules>,
+    /// Key formats to normalize each source's keys to, e.g. screaming_snake
+    key_formats: Option<keyformat::PerSource<keyformat::KeyFormat>>,
+    /// Hierarchy separator of the keys, e.g. __ or /, which (like dots)
+    /// separates the levels of the model
+    separator: Option<String>,
+    /// Or per source
+    separators: Option<keyformat::PerSource<Option<String>>>,
+    /// Accounts to also fetch the parameters & secrets from
+    accounts: Option<Vec<accounts::AccountSpec>>,
+    limits: Option<limits::LimitsConfig>,
+    /// Checked against the merged model before rendering
+    schema: Option<schema::SchemaConfig>,
+    /// Base directory for relative outputs (itself relative to the config
+    /// file). Defaults to the current directory.
+    out_base: Option<PathBuf>,
+    /// Where to stage outputs before renaming them into place (relative to
+    /// the config file). Defaults to alongside each output.
+    temp_dir: Option<PathBuf>,
+    /// Backups kept of each output, defaults to 1
+    backups: Option<usize>,
+    notifications: Option<notify::NotificationConfig>,
+    /// Publish CloudWatch metrics for each run
+    metrics: Option<metrics::MetricsConfig>,
+    /// Drop privileges after fetching, before rendering & writing
+    run_as: Option<privileges::RunAs>,
+    /// Make "true", "false" & numbers native JSON values in the model
+    coerce_types: Option<bool>,
+    /// Model keys templates may reference (checked by lint)
+    key_schema: Option<Vec<String>>,
     templates: Vec<TemplateSpec>,
 }
 
+impl Config {
+    fn fetch_concurrency(&self) -> usize {
+        self.fetch_concurrency.unwrap_or(DEFAULT_FETCH_CONCURRENCY).max(1)
+    }
+
+    /// Whether fetching needs AWS credentials at all.
+    fn uses_aws(&self) -> bool {
+        self.parameter_store_prefixes.is_some() || self.parameters.is_some() || self.parameter_filters.is_some() || self.hierarchy.is_some()
+            || self.secrets.is_some() || self.secret_prefixes.is_some() || self.secret_filters.is_some()
+            || self.appconfig.is_some() || self.s3_objects.is_some() || self.dynamodb.is_some()
+            || self.cloudformation_stacks.is_some() || self.certificates.is_some()
+            || self.rds_iam_tokens.is_some() || self.kms.is_some() || self.accounts.is_some()
+    }
+}
+
+/// Endpoint URLs to use instead of the defaults, per service.
+#[derive(Debug, Deserialize)]
+struct EndpointsSpec {
+    ssm: Option<String>,
+    secretsmanager: Option<String>,
+}
+
+/// The SDK config to use with a service, with its endpoint overridden if given.
+fn endpoint_config(sdk_config: &SdkConfig, url: Option<&str>) -> SdkConfig {
+    match url {
+        Some(url) => sdk_config.to_builder().endpoint_url(url).build(),
+        None => sdk_config.clone()
+    }
+}
+
+/// The SDK config for a source in another region or account, if it's in one.
+async fn source_config(sdk_config: &SdkConfig, roles: &accounts::RoleConfigs, region: Option<&str>,
+                       role_arn: Option<&str>, external_id: Option<&str>, session_name: Option<&str>) -> SdkConfig {
+    let sdk_config = match region {
+        Some(region) => sdk_config.to_builder().region(Region::new(region.to_owned())).build(),
+        None => sdk_config.clone()
+    };
+    let role_arn = match role_arn {
+        Some(role_arn) => role_arn,
+        None => return sdk_config
+    };
+
+    // STS is called at its own endpoint, not the source's
+    let endpoint_url = sdk_config.endpoint_url().map(str::to_owned);
+    let mut base_config = sdk_config.to_builder();
+    base_config.set_endpoint_url(None);
+    let mut role_config = roles.get(&base_config.build(), role_arn, external_id, session_name).await.to_builder();
+    role_config.set_endpoint_url(endpoint_url);
+    role_config.build()
+}
+
+/// The prefixes each source's keys can be put under by the config, which
+/// key_formats & separators leave alone.
+fn key_prefixes(config: &Config) -> keyformat::PerSource<Vec<String>> {
+    let parameter_store = keyformat::prefixes(config.parameter_store_prefixes.as_deref());
+    let secrets_manager = keyformat::prefixes(config.secrets.as_deref());
+    let accounts = config.accounts.as_deref().unwrap_or_default().iter()
+        .flat_map(|spec| {
+            let account = format!("accounts.{}", spec.name);
+            let namespaced = parameter_store.iter().chain(&secrets_manager).map(|p| format!("{}.{}", account, p)).collect::<Vec<_>>();
+            std::iter::once(account).chain(namespaced)
+        })
+        .collect();
+    keyformat::PerSource {
+        files: keyformat::prefixes(config.files.as_deref()),
+        sops: keyformat::prefixes(config.sops.as_deref()),
+        appconfig: keyformat::prefixes(config.appconfig.as_deref()),
+        certificates: keyformat::prefixes(config.certificates.as_deref()),
+        vault: config.vault.as_ref().map(|vault| vault.key_prefixes()).unwrap_or_default(),
+        doppler: keyformat::prefixes(config.doppler.as_deref()),
+        exec: keyformat::prefixes(config.exec.as_deref()),
+        http: keyformat::prefixes(config.http.as_deref()),
+        sql: keyformat::prefixes(config.sql.as_deref()),
+        kubernetes: keyformat::prefixes(config.kubernetes.as_deref()),
+        plugins: keyformat::prefixes(config.plugins.as_deref()),
+        gcp_secrets: keyformat::prefixes(config.gcp_secrets.as_deref()),
+        env: config.env.as_ref().and_then(KeyPrefix::key_prefix).into_iter().collect(),
+        parameter_store,
+        secrets_manager,
+        accounts,
+        ..Default::default()
+    }
+}
+
+/// Puts the keys under the namespace, if there is one.
+fn namespaced(namespace: Option<&str>, data: HashMap<String, String>) -> HashMap<String, String> {
+    match namespace {
+        Some(namespace) => data.into_iter().map(|(k, v)| (format!("{}.{}", namespace, k), v)).collect(),
+        None => data
+    }
+}
+
 fn trim_prefix<'a>(prefix : &str, s: &'a str) -> &'a str {
     &s[prefix.len()+1..]
 }
 
-async fn get_parameterstore_properties(config: &SdkConfig, prefixes: &[String]) -> Result<HashMap<String, String>> {
+/// Fetches everything under one prefix. When watching, only parameters
+/// whose version changed are fetched again.
+async fn get_prefix_properties(config: &SdkConfig, roles: &accounts::RoleConfigs, spec: &PrefixSpec,
+                               watch: Option<watch::Scope<'_>>) -> Result<HashMap<String, String>> {
     let mut data = HashMap::new();
 
-    let client = aws_sdk_ssm::Client::new(config);
+    let client = aws_sdk_ssm::Client::new(&source_config(config, roles, spec.region.as_deref(),
+        spec.role_arn.as_deref(), spec.external_id.as_deref(), spec.session_name.as_deref()).await);
+    let prefix = spec.path.strip_suffix('/').unwrap_or(&spec.path);
 
-    for prefix in prefixes {
-        let prefix = prefix.strip_suffix('/').unwrap_or(prefix);
-        let prefix_with_slash = {
-            let mut s = String::with_capacity(prefix.len() + 1);
-            s.push_str(prefix);
-            s.push('/');
-            s
-        };
+    // Filter by type server-side, so excluded SecureStrings are never fetched
+    let type_filter = match spec.secure_strings {
+        SecureStrings::Include => None,
+        SecureStrings::Exclude => Some(vec!["String", "StringList"]),
+        SecureStrings::Only => Some(vec!["SecureString"]),
+    };
+    let mut filters = Vec::new();
+    if let Some(types) = type_filter {
+        filters.push(ParameterStringFilter::builder()
+            .key("Type")
+            .option("Equals")
+            .set_values(Some(types.into_iter().map(str::to_owned).collect()))
+            .build()?);
+    }
+    if let Some(label) = &spec.label {
+        filters.push(ParameterStringFilter::builder()
+            .key("Label")
+            .option("Equals")
+            .values(label)
+            .build()?);
+    }
 
-        let mut next_token: Option<String> = None;
+    // Labels can move between versions, so those are always fetched afresh
+    let watch = watch.filter(|_| spec.label.is_none());
+    let watch_key = format!("{:?}", spec);
+    let parameters = match watch.and_then(|watch| watch.parameters(&watch_key)) {
+        Some(previous) => refresh_prefix(&client, spec, prefix, &filters, previous).await?,
+        None => fetch_prefix(&client, spec, prefix, &filters).await?
+    };
+    if let Some(watch) = watch {
+        watch.set_parameters(&watch_key, parameters.iter().filter_map(|p| Some((p.name.clone()?, p.clone()))).collect());
+    }
 
-        loop {
-            let params = client.get_parameters_by_path()
-                .path(&prefix_with_slash)
-                .with_decryption(true)
-                .set_next_token(next_token) // It's an Option, so use this instead of next_token()
-                .send().await.with_context(|| format!("Failed to retrieve parameter {}", prefix))?;
-            // let params = client.get_parameters_by_path(GetParametersByPathRequest {
-            //     path: prefix_with_slash.clone(),
-            //     next_token,
-            //     ..Default::default()
-            // }).await.with_context(|| format!("Failed to retrieve parameter {}", prefix))?;
-
-            if let Some(parameters) = params.parameters {
-                for p in &parameters {
-                    let name = match &p.name {
-                        Some(name) => name,
-                        None => continue // No name? Skip
-                    };
-                    let value = match &p.value {
-                        Some(value) => value,
-                        None => continue // No value? Skip
-                    };
-                    data.insert(trim_prefix(prefix, name).to_owned(), value.clone());
-                }
+    for p in &parameters {
+        let name = match &p.name {
+            Some(name) => name,
+            None => continue // No name? Skip
+        };
+        let value = match &p.value {
+            Some(value) => value,
+            None => continue // No value? Skip
+        };
+        let key = trim_prefix(prefix, name);
+        let key = if spec.nested { key.replace('/', ".") } else { key.to_owned() };
+        if spec.metadata {
+            insert_parameter_metadata(&key, p, &mut data);
+        }
+        if spec.split_string_lists && p.r#type == Some(ParameterType::StringList) {
+            for (i, item) in value.split(',').enumerate() {
+                data.insert(format!("{}.{}", key, i), item.to_owned());
             }
+        } else {
+            insert_parameter_value(&key, value, spec.parse_json, &mut data);
+        }
+    }
 
-            next_token = match params.next_token {
-                Some(token) => Some(token),
-                None => break
-            };
+    if data.is_empty() {
+        match spec.on_empty {
+            OnEmpty::Ignore => (),
+            OnEmpty::Warn => eprintln!("WARNING: No parameters found under {}", prefix),
+            OnEmpty::Error => bail!("No parameters found under {}", prefix)
         }
     }
 
-    Ok(data)
+    Ok(namespaced(spec.namespace.as_deref(), data))
 }
 
-async fn get_secretsmanager_properties(config: &SdkConfig, secrets: &[String]) -> Result<HashMap<String, String>> {
-    let mut data = HashMap::new();
+/// Every parameter under the prefix, a page at a time.
+async fn fetch_prefix(client: &aws_sdk_ssm::Client, spec: &PrefixSpec, prefix: &str, filters: &[ParameterStringFilter]) -> Result<Vec<Parameter>> {
+    let mut parameters = Vec::new();
 
-    let client = aws_sdk_secretsmanager::Client::new(config);
+    let prefix_with_slash = {
+        let mut s = String::with_capacity(prefix.len() + 1);
+        s.push_str(prefix);
+        s.push('/');
+        s
+    };
 
-    for secret in secrets {
-        let result = match client.get_secret_value()
-            .secret_id(secret)
-            .send().await.with_context(|| format!("Failed to get secret {}", secret)) {
-            Ok(response) => response,
-            Err(e) => {
-                // Ignore if it's ResourceNotFound
-                if e.root_cause().downcast_ref::<ResourceNotFoundException>().is_some() {
-                    continue;
+    let mut next_token: Option<String> = None;
+
+    loop {
+        let mut throttled = 0;
+        let params = loop {
+            let result = client.get_parameters_by_path()
+                .path(&prefix_with_slash)
+                .with_decryption(spec.decrypt)
+                .recursive(spec.recursive)
+                .set_max_results(spec.max_results)
+                .set_parameter_filters(Some(filters.to_vec()).filter(|f| !f.is_empty()))
+                .set_next_token(next_token.clone()) // It's an Option, so use this instead of next_token()
+                .send().await;
+            match result {
+                // Beyond the SDK's own retries, so a busy account doesn't fail the run
+                Err(e) if retry::is_throttling(&e) && throttled < retry::THROTTLE_RETRIES => {
+                    throttled += 1;
+                    eprintln!("WARNING: Throttled retrieving parameters {}, retrying", prefix);
+                    tokio::time::sleep(retry::backoff(throttled)).await;
                 }
-                // Everything else
-                return Err(e);
+                // Most likely kms:Decrypt, which String parameters don't need
+                Err(e) if spec.decrypt && e.code() == Some("AccessDeniedException") =>
+                    break Err(e).with_context(|| format!("Failed to retrieve parameter {} (without kms:Decrypt, set decrypt: false or secure_strings: exclude)", prefix))?,
+                result => break result.with_context(|| format!("Failed to retrieve parameter {}", prefix))?
             }
         };
+        // let params = client.get_parameters_by_path(GetParametersByPathRequest {
+        //     path: prefix_with_slash.clone(),
+        //     next_token,
+        //     ..Default::default()
+        // }).await.with_context(|| format!("Failed to retrieve parameter {}", prefix))?;
 
-        // Only deal with strings
-        match result.secret_string {
-            Some(s) => {
-                match serde_json::from_str::<Value>(&s) {
-                    Ok(Value::Object(map)) => {
-                        for (k,jv) in map {
-                            match jv {
-                                Value::String(v) => { data.insert(k, v); }
-                                _ => eprintln!("WARNING: Secret {}/{} value not JSON string", secret, k)
-                            }
-                        }
-                    }
-                    _ => eprintln!("WARNING: Secret {} value not JSON object", secret)
-                }
-            }
-            None => eprintln!("WARNING: Secret {} value not a string", secret)
+        parameters.extend(params.parameters.unwrap_or_default());
+
+        next_token = match params.next_token {
+            Some(token) => Some(token),
+            None => break
+        };
+
+        if let Some(ms) = spec.page_delay_ms {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
         }
     }
 
-    Ok(data)
+    Ok(parameters)
 }
 
-fn merge_properties(properties: Vec<HashMap<String, String>>) -> HashMap<String, String> {
-    let mut merged = HashMap::new();
+/// Every parameter under the prefix, as last time for those whose version
+/// is the same. Only the rest are fetched, as described versions are much
+/// cheaper than values.
+async fn refresh_prefix(client: &aws_sdk_ssm::Client, spec: &PrefixSpec, prefix: &str, filters: &[ParameterStringFilter],
+                        mut previous: HashMap<String, Parameter>) -> Result<Vec<Parameter>> {
+    let mut filters = filters.to_vec();
+    filters.push(ParameterStringFilter::builder()
+        .key("Path")
+        .option(if spec.recursive { "Recursive" } else { "OneLevel" })
+        .values(if prefix.is_empty() { "/" } else { prefix })
+        .build()?);
+    let versions = describe_versions(client, filters).await
+        .with_context(|| format!("Failed to describe parameters under {}", prefix))?;
 
-    for prop in properties {
-        for (k,v) in prop {
-            merged.insert(k, v);
+    let mut parameters = Vec::new();
+    let mut changed = Vec::new();
+    for (name, version) in versions {
+        match previous.remove(&name) {
+            Some(p) if p.version == version => parameters.push(p),
+            _ => changed.push(name)
         }
     }
+    for batch in changed.chunks(GET_PARAMETERS_BATCH_SIZE) {
+        let result = client.get_parameters()
+            .set_names(Some(batch.to_vec()))
+            .with_decryption(spec.decrypt)
+            .send().await.with_context(|| format!("Failed to retrieve parameters under {}", prefix))?;
+        parameters.extend(result.parameters.unwrap_or_default());
+    }
+    parameters.sort_by(|a, b| a.name.cmp(&b.name));
 
-    merged
+    Ok(parameters)
 }
 
-async fn get_properties(config: &SdkConfig, param_store_prefixes: &[String], secrets: &[String], verbosity: u8) -> Result<HashMap<String, String>> {
-    // Retrieve from Parameter Store
-    let ps_fut = get_parameterstore_properties(config, param_store_prefixes);
+/// The current version of every parameter matching the filters, by name.
+async fn describe_versions(client: &aws_sdk_ssm::Client, filters: Vec<ParameterStringFilter>) -> Result<HashMap<String, i64>> {
+    let pages = client.describe_parameters()
+        .set_parameter_filters(Some(filters))
+        .max_results(DESCRIBE_PARAMETERS_BATCH_SIZE as i32)
+        .into_paginator().send()
+        .try_collect().await?;
 
-    // Retrieve from Secrets Manager
-    let sm_fut = get_secretsmanager_properties(config, secrets);
+    Ok(pages.into_iter()
+        .flat_map(|page| page.parameters.unwrap_or_default())
+        .filter_map(|p| Some((p.name?, p.version)))
+        .collect())
+}
 
-    // TODO Could probably use try_join! here... But how?
-    let (ps_res, sm_res) = join!(ps_fut, sm_fut);
+/// Fetches up to `concurrency` prefixes at a time, merged in order.
+async fn get_parameterstore_properties(config: &SdkConfig, roles: &accounts::RoleConfigs, prefixes: &[PrefixSpec], concurrency: usize,
+                                       watch: Option<watch::Scope<'_>>) -> Result<HashMap<String, String>> {
+    let results: Vec<HashMap<String, String>> = stream::iter(prefixes)
+        .map(|spec| get_prefix_properties(config, roles, spec, watch))
+        .buffered(concurrency)
+        .try_collect().await?;
+    Ok(merge_properties(results))
+}
 
-    let ps_data = ps_res?;
-    let sm_data = sm_res?;
+/// Parameter Store prefixes, fetched `concurrency` at a time.
+struct ParameterStoreSource<'a> {
+    sdk_config: &'a SdkConfig,
+    roles: &'a accounts::RoleConfigs,
+    prefixes: &'a [PrefixSpec],
+    concurrency: usize,
+    watch: Option<watch::Scope<'a>>,
+}
 
-    if verbosity > 1 {
-        println!("ps_data = {:#?}", ps_data);
-        println!("sm_data = {:#?}", sm_data);
+impl Source for ParameterStoreSource<'_> {
+    fn name(&self) -> String {
+        "Parameter Store".to_owned()
     }
 
-    // Merge results (Secrets Manager takes precedence)
-    let data = merge_properties(vec![ps_data, sm_data]);
-    if verbosity > 0 { println!("data = {:#?}", data); }
+    fn fetch(&self) -> LocalBoxFuture<'_, Result<HashMap<String, String>>> {
+        get_parameterstore_properties(self.sdk_config, self.roles, self.prefixes, self.concurrency, self.watch).boxed_local()
+    }
+}
 
-    Ok(data)
+/// Stores a parameter's value, or with `parse_json` the JSON object or array
+/// it holds, flattened beneath its key. Other values are stored as they are.
+fn insert_parameter_value(key: &str, value: &str, parse_json: bool, data: &mut HashMap<String, String>) {
+    if parse_json {
+        if let Ok(doc @ (Value::Object(_) | Value::Array(_))) = serde_json::from_str::<Value>(value) {
+            model::flatten_value(key, &doc, data);
+            return;
+        }
+    }
+    data.insert(key.to_owned(), value.to_owned());
 }
 
-fn main() -> Result<()> {
-    // Parse command line args
-    let opt = Opt::parse();
+/// Stores a parameter's version, last modified date, type & ARN under
+/// `__meta.<key>`.
+fn insert_parameter_metadata(key: &str, p: &Parameter, data: &mut HashMap<String, String>) {
+    let mut insert = |field: &str, value: String| { data.insert(format!("{}.{}.{}", META_KEY, key, field), value); };
 
-    // Parse config file
-    let config_bytes = std::fs::read(&opt.config)
-        .with_context(|| format!("Error reading config {}", opt.config.display()))?;
-    let config: Config = serde_yaml::from_str(&String::from_utf8_lossy(&config_bytes))
-        .with_context(|| format!("Error parsing config {}", opt.config.display()))?;
+    insert("version", p.version.to_string());
+    if let Some(date) = p.last_modified_date.and_then(|d| d.fmt(DateTimeFormat::DateTime).ok()) {
+        insert("last_modified_date", date);
+    }
+    if let Some(t) = &p.r#type {
+        insert("type", t.as_str().to_owned());
+    }
+    if let Some(arn) = &p.arn {
+        insert("arn", arn.clone());
+    }
+}
 
-    // Only need to selectively go async
-    let rt = Runtime::new().unwrap();
-    let base_sdk_config = rt.block_on(aws_config::load_from_env());
+/// Fetches parameters by name, as many at a time as GetParameters allows.
+/// When watching, those (without a version or label) whose version hasn't
+/// changed aren't fetched again.
+async fn get_parameter_properties(config: &SdkConfig, parameters: &[ParameterSpec], watch: Option<watch::Scope<'_>>) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
 
-    // Determine region. Priority: command line > config file > environment > profile
-    let region = match opt.region {
-        Some(region_str) => Some(Region::new(region_str)),
-        _ => config.region.map(Region::new)
-    };
+    if parameters.is_empty() {
+        return Ok(data);
+    }
 
-    // Switch to new region, if needed
-    let sdk_config = match region {
-        Some(region) => base_sdk_config.into_builder().region(region).build(),
-        _ => base_sdk_config
-    };
+    let client = aws_sdk_ssm::Client::new(config);
 
-    // Retrieve all properties from AWS
-    let param_store_prefixes = config.parameter_store_prefixes.unwrap_or_default();
-    let secrets = config.secrets.unwrap_or_default();
+    let mut unchanged = HashMap::new();
+    if let Some(mut previous) = watch.and_then(|watch| watch.parameters(PARAMETERS_WATCH_KEY)) {
+        let names: Vec<String> = parameters.iter()
+            .filter(|spec| spec.selector.is_none())
+            .map(|spec| spec.name.clone())
+            .collect();
+        for batch in names.chunks(DESCRIBE_PARAMETERS_BATCH_SIZE) {
+            let filter = ParameterStringFilter::builder()
+                .key("Name")
+                .option("Equals")
+                .set_values(Some(batch.to_vec()))
+                .build()?;
+            let versions = describe_versions(&client, vec![filter]).await.context("Failed to describe parameters")?;
+            for (name, version) in versions {
+                if let Some(p) = previous.remove(&name).filter(|p| p.version == version) {
+                    unchanged.insert(name, p);
+                }
+            }
+        }
+    }
+    for spec in parameters.iter().filter(|spec| spec.selector.is_none()) {
+        if let Some(p) = unchanged.get(&spec.name) {
+            if let Some(value) = &p.value {
+                insert_parameter_value(&spec.key, value, spec.parse_json, &mut data);
+            }
+            if spec.metadata {
+                insert_parameter_metadata(&spec.key, p, &mut data);
+            }
+        }
+    }
+    let parameters: Vec<&ParameterSpec> = parameters.iter()
+        .filter(|spec| spec.selector.is_some() || !unchanged.contains_key(&spec.name))
+        .collect();
 
-    let data = rt.block_on(get_properties(&sdk_config, &param_store_prefixes, &secrets, opt.verbose))?;
+    for batch in parameters.chunks(GET_PARAMETERS_BATCH_SIZE) {
+        let result = client.get_parameters()
+            .set_names(Some(batch.iter().map(|p| p.selector.clone().unwrap_or_else(|| p.name.clone())).collect()))
+            .with_decryption(true)
+            .send().await.context("Failed to retrieve parameters")?;
 
-    // Generate (JSON) template model
-    let model = model::build_template_model(data);
-    if opt.verbose > 1 { println!("model = {:#?}", model); }
+        for name in result.invalid_parameters.unwrap_or_default() {
+            let required = batch.iter()
+                .filter(|spec| spec.name == name || spec.selector.as_ref() == Some(&name))
+                .map(|spec| spec.required)
+                .max().flatten();
+            match required {
+                Some(true) => bail!("Required parameter {} not found", name),
+                Some(false) => (),
+                None => eprintln!("WARNING: Parameter {} not found", name)
+            }
+        }
 
-    // Initialize template engine
-    let mut handlebars = Handlebars::new();
-    handlebars.register_escape_fn(no_escape);
-    handlebars.set_strict_mode(true);
+        for p in result.parameters.unwrap_or_default() {
+            let (name, value) = match (&p.name, &p.value) {
+                (Some(name), Some(value)) => (name, value),
+                _ => continue // Incomplete? Skip
+            };
+            // Selected versions may come back with or without their selector
+            for spec in batch.iter().filter(|spec| &spec.name == name || spec.selector.as_ref() == Some(name)) {
+                insert_parameter_value(&spec.key, value, spec.parse_json, &mut data);
+                if spec.metadata {
+                    insert_parameter_metadata(&spec.key, &p, &mut data);
+                }
+            }
+            if let Some(watch) = watch.filter(|_| batch.iter().any(|spec| &spec.name == name && spec.selector.is_none())) {
+                watch.add_parameters(PARAMETERS_WATCH_KEY, [(name.clone(), p.clone())]);
+            }
+        }
+    }
 
-    // Base directory of config file (for relative templates)
-    let mut config_dir = opt.config.canonicalize().unwrap();
-    config_dir.pop();
+    Ok(data)
+}
 
-    // Render the templates
-    for ts in &config.templates {
-        // Determine template path
-        let template_path = if ts.src.is_relative() {
-            // Relative to config base dir
-            let mut base = config_dir.clone();
-            base.push(&ts.src);
-            base
-        } else {
-            // Absolute path
-            ts.src.clone()
-        };
+/// A DescribeParameters filter, e.g. `{key: tag:app, values: [billing]}`.
+#[derive(Debug, Deserialize)]
+struct ParameterFilterSpec {
+    key: String,
+    /// Equals (default for tags), BeginsWith, ...
+    option: Option<String>,
+    values: Vec<String>,
+}
+
+/// Fetches every parameter matching all of the filters.
+async fn get_discovered_parameter_properties(config: &SdkConfig, filters: &[ParameterFilterSpec], watch: Option<watch::Scope<'_>>) -> Result<HashMap<String, String>> {
+    if filters.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let client = aws_sdk_ssm::Client::new(config);
+
+    let filters = filters.iter()
+        .map(|f| ParameterStringFilter::builder()
+            .key(&f.key)
+            .set_option(f.option.clone())
+            .set_values(Some(f.values.clone()))
+            .build())
+        .collect::<Result<Vec<_>, _>>()?;
+    let pages = client.describe_parameters()
+        .set_parameter_filters(Some(filters))
+        .into_paginator().send()
+        .try_collect().await.context("Failed to describe parameters")?;
+
+    let mut names: Vec<String> = pages.into_iter()
+        .flat_map(|page| page.parameters.unwrap_or_default())
+        .filter_map(|p| p.name)
+        .collect();
+    names.sort();
+
+    let keys = discovered_keys(&names);
+    let specs: Vec<ParameterSpec> = names.into_iter().zip(keys)
+        .map(|(name, key)| ParameterEntry::Spec { name, key: Some(key), label: None, version: None, metadata: None, required: None, parse_json: None }.into())
+        .collect();
+    get_parameter_properties(config, &specs, watch).await
+}
+
+/// Keys for discovered parameters: their names less the path they all
+/// share, nested at each /, e.g. /app/db/host & /app/cache/host are db.host
+/// & cache.host. Just the last segment if there's only one.
+fn discovered_keys(names: &[String]) -> Vec<String> {
+    let segments: Vec<Vec<&str>> = names.iter()
+        .map(|name| name.split('/').filter(|s| !s.is_empty()).collect())
+        .collect();
+    let shared = segments.iter()
+        .map(|s| &s[..s.len().saturating_sub(1)])
+        .reduce(|a, b| &a[..a.iter().zip(b).take_while(|(a, b)| a == b).count()])
+        .map_or(0, <[&str]>::len);
+    segments.iter().map(|s| s[shared..].join(".")).collect()
+}
+
+fn insert_secret_string(secret: &str, s: &str, suffix: &str, data: &mut HashMap<String, String>) {
+    match serde_json::from_str::<Value>(s) {
+        Ok(Value::Object(map)) => {
+            for (k,jv) in map {
+                match jv {
+                    Value::String(v) => { data.insert(format!("{}{}", k, suffix), v); }
+                    // Structured values become dotted keys, i.e. nested in the model
+                    Value::Object(_) => {
+                        let mut nested = HashMap::new();
+                        model::flatten_value(&k, &jv, &mut nested);
+                        data.extend(nested.into_iter().map(|(k, v)| (format!("{}{}", k, suffix), v)));
+                    }
+                    _ => eprintln!("WARNING: Secret {}/{} value not JSON string", secret, k)
+                }
+            }
+        }
+        _ => eprintln!("WARNING: Secret {} value not JSON object", secret)
+    }
+}
 
-        if opt.verbose > 0 { println!("Rendering template {}...", template_path.display()); }
+#[derive(Clone)]
+enum SecretValue {
+    String(String),
+    Binary(Vec<u8>),
+}
+
+fn insert_secret(spec: &SecretSpec, value: &SecretValue, suffix: &str, data: &mut HashMap<String, String>) {
+    match value {
+        SecretValue::String(s) => match &spec.key {
+            Some(key) => { data.insert(format!("{}{}", key, suffix), s.clone()); }
+            None => insert_secret_string(&spec.id, s, suffix, data)
+        },
+        SecretValue::Binary(b) => {
+            let key = spec.binary_key.as_deref().or(spec.key.as_deref()).unwrap_or(&spec.id);
+            data.insert(format!("{}{}", key, suffix), BASE64.encode(b));
+        }
+    }
+}
+
+/// Returns the secret's version ID & value at the given stage (default
+/// AWSCURRENT) or version, or None if it (or the stage) doesn't exist.
+async fn get_secret(client: &aws_sdk_secretsmanager::Client, secret: &str, stage: Option<&str>, version_id: Option<&str>) -> Result<Option<(String, SecretValue)>> {
+    let result = match client.get_secret_value()
+        .secret_id(secret)
+        .set_version_stage(stage.map(str::to_owned))
+        .set_version_id(version_id.map(str::to_owned))
+        .send().await.with_context(|| format!("Failed to get secret {}", secret)) {
+        Ok(response) => response,
+        Err(e) => {
+            // Ignore if it's ResourceNotFound
+            if e.root_cause().downcast_ref::<ResourceNotFoundException>().is_some() {
+                return Ok(None);
+            }
+            // Everything else
+            return Err(e);
+        }
+    };
 
-        let mut template_file = File::open(&template_path)
-            .with_context(|| format!("Error reading template {}", template_path.display()))?;
+    let version_id = result.version_id.unwrap_or_default();
+    match (result.secret_string, result.secret_binary) {
+        (Some(s), _) => Ok(Some((version_id, SecretValue::String(s)))),
+        (None, Some(b)) => Ok(Some((version_id, SecretValue::Binary(b.into_inner())))),
+        (None, None) => {
+            eprintln!("WARNING: Secret {} has no value", secret);
+            Ok(None)
+        }
+    }
+}
 
-        let mut result: Vec<u8> = Vec::new();
-        handlebars.render_template_source_to_write(&mut template_file, &model, &mut result)
-            .with_context(|| format!("Error rendering template {}", template_path.display()))?;
+/// Waits (briefly) while a version labelled AWSPENDING is yet to become AWSCURRENT.
+async fn wait_for_rotation(client: &aws_sdk_secretsmanager::Client, secret: &str) -> Result<()> {
+    for _ in 0..ROTATION_WAIT_ATTEMPTS {
+        let description = client.describe_secret()
+            .secret_id(secret)
+            .send().await.with_context(|| format!("Failed to describe secret {}", secret))?;
 
-        if !opt.dryrun {
-            output::output(&ts.out, &result, opt.nobackup, opt.verbose)?;
+        let rotating = description.version_ids_to_stages.unwrap_or_default().values()
+            .any(|stages| stages.iter().any(|s| s == "AWSPENDING") && !stages.iter().any(|s| s == "AWSCURRENT"));
+        if !rotating {
+            return Ok(());
         }
+
+        tokio::time::sleep(ROTATION_WAIT_DELAY).await;
     }
 
+    eprintln!("WARNING: Secret {} still rotating, using AWSCURRENT", secret);
     Ok(())
 }
+
+/// Returns the secret as last time if the version (ID or stage) asked for
+/// is the same, otherwise fetches it.
+async fn get_watched_secret(client: &aws_sdk_secretsmanager::Client, secret: &SecretSpec, stage: Option<&str>,
+                            versions: Option<&HashMap<String, Vec<String>>>, watch: Option<(watch::Scope<'_>, String)>) -> Result<Option<SecretValue>> {
+    let version_id = secret.version_id.as_deref().filter(|_| stage.is_none());
+    let stage = stage.or(secret.version_stage.as_deref());
+    let listed = version_id.map(str::to_owned).or_else(|| {
+        let stage = stage.unwrap_or("AWSCURRENT");
+        versions?.iter().find(|(_, stages)| stages.iter().any(|s| s == stage)).map(|(id, _)| id.clone())
+    });
+    if let (Some(listed), Some((watch, key))) = (&listed, &watch) {
+        if let Some((_, value)) = watch.secret(key).filter(|(id, _)| id == listed) {
+            return Ok(Some(value));
+        }
+    }
+
+    let result = get_secret(client, &secret.id, stage, version_id).await?;
+    if let (Some((version_id, value)), Some((watch, key))) = (&result, &watch) {
+        watch.set_secret(key, version_id.clone(), value.clone());
+    }
+    Ok(result.map(|(_, value)| value))
+}
+
+/// Fetches one secret (and its pending version, if asked). When watching,
+/// its versions (by ID, with their stages) are listed beforehand, & only
+/// new versions are fetched.
+async fn get_secret_properties(config: &SdkConfig, roles: &accounts::RoleConfigs, secret: &SecretSpec,
+                               versions: Option<&HashMap<String, Vec<String>>>, watch: Option<watch::Scope<'_>>) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    let client = aws_sdk_secretsmanager::Client::new(&source_config(config, roles, secret.region.as_deref(),
+        secret.role_arn.as_deref(), secret.external_id.as_deref(), secret.session_name.as_deref()).await);
+    if let Some(Rotation::Wait) = secret.rotation {
+        wait_for_rotation(&client, &secret.id).await?;
+    }
+
+    let watch_key = format!("{:?}", secret);
+    let current = match get_watched_secret(&client, secret, None, versions, watch.map(|watch| (watch, watch_key.clone()))).await? {
+        Some(value) => value,
+        None if secret.required == Some(true) => bail!("Required secret {} not found", secret.id),
+        None => return Ok(data)
+    };
+    insert_secret(secret, &current, "", &mut data);
+
+    if let Some(Rotation::Pending) = secret.rotation {
+        // Outside of rotation, pending is the same as current
+        let rotating = versions.is_none_or(|versions| versions.values().flatten().any(|stage| stage == "AWSPENDING"));
+        let pending = match rotating {
+            true => get_watched_secret(&client, secret, Some("AWSPENDING"), versions,
+                watch.map(|watch| (watch, format!("{}/AWSPENDING", watch_key)))).await?,
+            false => None
+        };
+        insert_secret(secret, pending.as_ref().unwrap_or(&current), PENDING_SUFFIX, &mut data);
+    }
+
+    Ok(namespaced(secret.namespace.as_deref(), data))
+}
+
+/// The name a secret ID (name or ARN) lists as, or the start of it if it's
+/// a partial ARN.
+fn secret_name(id: &str) -> &str {
+    match id.split_once(":secret:") {
+        // Full ARNs end in a hyphen & 6 random characters
+        Some((_, name)) => match name.rsplit_once('-') {
+            Some((name, suffix)) if suffix.len() == 6 => name,
+            _ => name
+        },
+        None => id
+    }
+}
+
+/// Whether a listed secret is the one with this ID.
+fn is_secret(entry: &SecretListEntry, id: &str) -> bool {
+    entry.name.as_deref() == Some(id) || entry.arn.as_deref()
+        .is_some_and(|arn| arn == id || arn.strip_prefix(id).is_some_and(|rest| rest.len() == 7 && rest.starts_with('-')))
+}
+
+/// Each secret's versions (by ID) with their stages, listed as many secrets
+/// at a time as ListSecrets allows, rather than described one by one.
+async fn list_secret_versions(config: &SdkConfig, roles: &accounts::RoleConfigs, secrets: &[SecretSpec]) -> Result<Vec<Option<HashMap<String, Vec<String>>>>> {
+    let mut versions = vec![None; secrets.len()];
+
+    // Listed in the account & region they're in
+    let mut groups: BTreeMap<_, Vec<usize>> = BTreeMap::new();
+    for (i, secret) in secrets.iter().enumerate() {
+        groups.entry((&secret.region, &secret.role_arn, &secret.external_id, &secret.session_name)).or_default().push(i);
+    }
+    for ((region, role_arn, external_id, session_name), indices) in groups {
+        let client = aws_sdk_secretsmanager::Client::new(&source_config(config, roles, region.as_deref(),
+            role_arn.as_deref(), external_id.as_deref(), session_name.as_deref()).await);
+        for batch in indices.chunks(LIST_SECRETS_BATCH_SIZE) {
+            // Names match as prefixes, so this may list others too
+            let filter = Filter::builder()
+                .key(FilterNameStringType::Name)
+                .set_values(Some(batch.iter().map(|&i| secret_name(&secrets[i].id).to_owned()).collect()))
+                .build();
+            let pages = client.list_secrets()
+                .filters(filter)
+                .into_paginator().send()
+                .try_collect().await.context("Failed to list secrets")?;
+            for entry in pages.into_iter().flat_map(|page| page.secret_list.unwrap_or_default()) {
+                for &i in batch.iter().filter(|&&i| is_secret(&entry, &secrets[i].id)) {
+                    versions[i] = entry.secret_versions_to_stages.clone();
+                }
+            }
+        }
+    }
+
+    Ok(versions)
+}
+
+/// Fetches up to `concurrency` secrets at a time, merged in order.
+async fn get_secretsmanager_properties(config: &SdkConfig, roles: &accounts::RoleConfigs, secrets: &[SecretSpec], concurrency: usize,
+                                       watch: Option<watch::Scope<'_>>) -> Result<HashMap<String, String>> {
+    // Nothing to compare the versions to the first time round
+    let versions = match watch {
+        Some(watch) if watch.has_secrets() => list_secret_versions(config, roles, secrets).await?,
+        _ => vec![None; secrets.len()]
+    };
+    let results: Vec<HashMap<String, String>> = stream::iter(secrets.iter().zip(&versions))
+        .map(|(secret, versions)| get_secret_properties(config, roles, secret, versions.as_ref(), watch))
+        .buffered(concurrency)
+        .try_collect().await?;
+    Ok(merge_properties(results))
+}
+
+/// Secrets Manager secrets, fetched `concurrency` at a time.
+struct SecretsManagerSource<'a> {
+    sdk_config: &'a SdkConfig,
+    roles: &'a accounts::RoleConfigs,
+    secrets: &'a [SecretSpec],
+    concurrency: usize,
+    watch: Option<watch::Scope<'a>>,
+}
+
+impl Source for SecretsManagerSource<'_> {
+    fn name(&self) -> String {
+        "Secrets Manager".to_owned()
+    }
+
+    fn fetch(&self) -> LocalBoxFuture<'_, Result<HashMap<String, String>>> {
+        get_secretsmanager_properties(self.sdk_config, self.roles, self.secrets, self.concurrency, self.watch).boxed_local()
+    }
+}
+
+/// A ListSecrets filter, e.g. `{key: tag-key, values: [team]}`.
+#[derive(Debug, Deserialize)]
+struct SecretFilterSpec {
+    key: String,
+    values: Vec<String>,
+}
+
+/// Names of the secrets matching the filters (all of them), in order.
+async fn list_secrets(client: &aws_sdk_secretsmanager::Client, filters: Vec<Filter>) -> Result<Vec<String>> {
+    let pages = client.list_secrets()
+        .set_filters(Some(filters))
+        .into_paginator().send()
+        .try_collect().await?;
+
+    let mut names: Vec<String> = pages.into_iter()
+        .flat_map(|page| page.secret_list.unwrap_or_default())
+        .filter_map(|entry| entry.name)
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Fetches every secret matching each of the prefixes, and the filters.
+async fn get_discovered_secret_properties(config: &SdkConfig, prefixes: &[String], filters: &[SecretFilterSpec], concurrency: usize,
+                                         watch: Option<watch::Scope<'_>>) -> Result<HashMap<String, String>> {
+    let client = aws_sdk_secretsmanager::Client::new(config);
+
+    let mut names = Vec::new();
+    for prefix in prefixes {
+        let filter = Filter::builder().key(FilterNameStringType::Name).values(prefix).build();
+        names.extend(list_secrets(&client, vec![filter]).await
+            .with_context(|| format!("Failed to list secrets with prefix {}", prefix))?);
+    }
+    if !filters.is_empty() {
+        let filters = filters.iter()
+            .map(|f| Filter::builder()
+                .key(FilterNameStringType::from(f.key.as_str()))
+                .set_values(Some(f.values.clone()))
+                .build())
+            .collect();
+        names.extend(list_secrets(&client, filters).await.context("Failed to list secrets")?);
+    }
+
+    let specs: Vec<SecretSpec> = names.into_iter().map(|id| SecretEntry::Id(id).into()).collect();
+    // None of them assume a role
+    get_secretsmanager_properties(config, &accounts::RoleConfigs::default(), &specs, concurrency, watch).await
+}
+
+fn merge_properties(properties: Vec<HashMap<String, String>>) -> HashMap<String, String> {
+    let mut merged = HashMap::new();
+
+    for prop in properties {
+        for (k,v) in prop {
+            merged.insert(k, v);
+        }
+    }
+
+    merged
+}
+
+/// Merges the sources' properties in order, doing as `on_conflict` says with
+/// keys that more than one defines differently. Also returns the source each
+/// key came from.
+fn merge_sources(sources: Vec<(&str, HashMap<String, String>)>, on_conflict: OnConflict) -> Result<(HashMap<String, String>, HashMap<String, String>)> {
+    let mut merged: HashMap<String, (String, &str)> = HashMap::new();
+    let mut conflicts: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+
+    for (source, prop) in sources {
+        for (k, v) in prop {
+            match merged.entry(k) {
+                Entry::Vacant(entry) => { entry.insert((v, source)); }
+                Entry::Occupied(mut entry) => {
+                    let (value, first) = entry.get();
+                    if *value != v {
+                        conflicts.entry(entry.key().clone()).or_insert_with(|| vec![first]).push(source);
+                    }
+                    if on_conflict != OnConflict::FirstWins {
+                        entry.insert((v, source));
+                    }
+                }
+            }
+        }
+    }
+
+    let report = || conflicts.iter()
+        .map(|(k, sources)| format!("{} (defined by {})", k, sources.join(", ")))
+        .collect::<Vec<_>>();
+    match on_conflict {
+        OnConflict::Error if !conflicts.is_empty() => bail!("Conflicting keys:\n  {}", report().join("\n  ")),
+        OnConflict::Warn => for conflict in report() {
+            eprintln!("WARNING: Conflicting key {}", conflict);
+        },
+        _ => ()
+    }
+
+    let provenance = merged.iter().map(|(k, (_, source))| (k.clone(), source.to_string())).collect();
+    Ok((merged.into_iter().map(|(k, (v, _))| (k, v)).collect(), provenance))
+}
+
+async fn get_account_properties(sdk_config: &SdkConfig, config: &Config, watch: Option<&watch::State>) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    let endpoints = config.endpoints.as_ref();
+    let concurrency = config.fetch_concurrency();
+
+    for spec in config.accounts.as_deref().unwrap_or_default() {
+        let account_config = accounts::account_config(sdk_config, spec).await;
+        let ssm_config = endpoint_config(&account_config, endpoints.and_then(|e| e.ssm.as_deref()));
+        let sm_config = endpoint_config(&account_config, endpoints.and_then(|e| e.secretsmanager.as_deref()));
+        let roles = accounts::RoleConfigs::default();
+        let scope = format!("accounts.{}", spec.name);
+        let watch = watch.map(|watch| watch::Scope::new(watch, &scope));
+        let sources: Vec<Box<dyn Source>> = vec![
+            Box::new(ParameterStoreSource { sdk_config: &ssm_config, roles: &roles, prefixes: config.parameter_store_prefixes.as_deref().unwrap_or_default(), concurrency, watch }),
+            Box::new(SecretsManagerSource { sdk_config: &sm_config, roles: &roles, secrets: config.secrets.as_deref().unwrap_or_default(), concurrency, watch }),
+        ];
+        let account_data = source::fetch_all(&sources).await
+            .with_context(|| format!("Error fetching from account {}", spec.name))?;
+        data.extend(accounts::namespace(spec, account_data));
+    }
+
+    Ok(data)
+}
+
+/// What the remote sources fetched (normalized), which is what --cache
+/// keeps. Files, sops files, commands, databases, plugins & the environment
+/// are always read afresh, as is everything done after merging.
+#[derive(Serialize, Deserialize)]
+struct RemoteData {
+    azure_blobs: HashMap<String, String>,
+    s3_objects: HashMap<String, String>,
+    dynamodb: HashMap<String, String>,
+    cloudformation_stacks: HashMap<String, String>,
+    appconfig: HashMap<String, String>,
+    parameter_store_prefixes: HashMap<String, String>,
+    parameter_filters: HashMap<String, String>,
+    parameters: HashMap<String, String>,
+    hierarchy: HashMap<String, String>,
+    secret_prefixes: HashMap<String, String>,
+    secrets: HashMap<String, String>,
+    certificates: HashMap<String, String>,
+    vault: HashMap<String, String>,
+    consul: HashMap<String, String>,
+    etcd: HashMap<String, String>,
+    doppler: HashMap<String, String>,
+    http: HashMap<String, String>,
+    kubernetes: HashMap<String, String>,
+    gcp_secrets: HashMap<String, String>,
+    accounts: HashMap<String, String>,
+}
+
+async fn get_properties(sdk_config: &SdkConfig, config: &Config, config_dir: &Path, verbosity: u8, explain: bool,
+                        cache_entry: Option<&cache::Entry<'_>>, watch: Option<&watch::State>) -> Result<HashMap<String, String>> {
+    let key_formats = config.key_formats.clone().unwrap_or_default();
+    let separators = config.separators.clone().unwrap_or_default();
+    let prefixes = key_prefixes(config);
+    let normalize = |format, separator: &Option<String>, prefixes: &[String], data| {
+        keyformat::apply(format, separator.as_deref().or(config.separator.as_deref()), prefixes, data)
+    };
+
+    // Read local files
+    let file_data = normalize(key_formats.files, &separators.files, &prefixes.files,
+        files::get_file_properties(config_dir, config.files.as_deref().unwrap_or_default())?);
+
+    // Decrypt sops files
+    let sops_fut = sops::get_sops_properties(sdk_config, config_dir, config.sops.as_deref().unwrap_or_default());
+
+    // Run commands
+    let exec_fut = exec::get_exec_properties(config_dir, config.exec.as_deref().unwrap_or_default());
+
+    // Query databases
+    let sql_fut = sql::get_sql_properties(config_dir, config.sql.as_deref().unwrap_or_default());
+
+    // Retrieve from plugins
+    let plugin_sources: Vec<Box<dyn Source>> = config.plugins.iter().flatten()
+        .map(|spec| Box::new(source::PluginSource { spec, region: sdk_config.region().map(|r| r.to_string()) }) as Box<dyn Source>)
+        .collect();
+    let plugin_fut = source::fetch_all(&plugin_sources);
+
+    // The rest can come from the cache
+    let remote_fut = async {
+        if let Some(remote) = cache_entry.and_then(|entry| entry.get(verbosity)) {
+            return Ok(remote);
+        }
+        // Retrieve from Azure Blob Storage
+        let az_fut = azure::get_azure_blob_properties(config.azure_blobs.as_deref().unwrap_or_default());
+
+        // Retrieve from S3
+        let s3_fut = s3::get_s3_properties(sdk_config, config.s3_objects.as_deref().unwrap_or_default());
+
+        // Retrieve from DynamoDB
+        let ddb_fut = dynamodb::get_dynamodb_properties(sdk_config, config.dynamodb.as_deref().unwrap_or_default());
+
+        // Retrieve from CloudFormation
+        let cfn_fut = cloudformation::get_stack_properties(sdk_config, config.cloudformation_stacks.as_deref().unwrap_or_default());
+
+        // Retrieve from AppConfig
+        let ac_fut = appconfig::get_appconfig_properties(sdk_config, config.appconfig.as_deref().unwrap_or_default());
+
+        let endpoints = config.endpoints.as_ref();
+        let concurrency = config.fetch_concurrency();
+        let ssm_config = endpoint_config(sdk_config, endpoints.and_then(|e| e.ssm.as_deref()));
+        let sm_config = endpoint_config(sdk_config, endpoints.and_then(|e| e.secretsmanager.as_deref()));
+        // Prefixes & secrets using the same role share its credentials
+        let roles = accounts::RoleConfigs::default();
+        let scope = watch.map(|watch| watch::Scope::new(watch, ""));
+
+        // Retrieve from Parameter Store
+        let ps_source = ParameterStoreSource { sdk_config: &ssm_config, roles: &roles, prefixes: config.parameter_store_prefixes.as_deref().unwrap_or_default(), concurrency, watch: scope };
+        let ps_fut = ps_source.fetch();
+
+        // Discover & retrieve parameters, e.g. by tag
+        let dps_fut = get_discovered_parameter_properties(&ssm_config, config.parameter_filters.as_deref().unwrap_or_default(), scope);
+
+        // Retrieve individual parameters
+        let param_fut = get_parameter_properties(&ssm_config, config.parameters.as_deref().unwrap_or_default(), scope);
+
+        // Retrieve the hierarchy from Parameter Store
+        let facts = hierarchy::facts(sdk_config, config.facts.as_ref());
+        let levels: Vec<PrefixSpec> = hierarchy::expand(config.hierarchy.as_deref().unwrap_or_default(), &facts, verbosity)?
+            .into_iter().map(|prefix| PrefixEntry::Path(prefix).into()).collect();
+        let hier_source = ParameterStoreSource { sdk_config: &ssm_config, roles: &roles, prefixes: &levels, concurrency, watch: scope };
+        let hier_fut = hier_source.fetch();
+
+        // Discover & retrieve from Secrets Manager
+        let dsm_fut = get_discovered_secret_properties(&sm_config,
+            config.secret_prefixes.as_deref().unwrap_or_default(), config.secret_filters.as_deref().unwrap_or_default(), concurrency, scope);
+
+        // Retrieve from Secrets Manager
+        let sm_source = SecretsManagerSource { sdk_config: &sm_config, roles: &roles, secrets: config.secrets.as_deref().unwrap_or_default(), concurrency, watch: scope };
+        let sm_fut = sm_source.fetch();
+
+        // Retrieve from ACM
+        let cert_fut = acm::get_certificate_properties(sdk_config, config.certificates.as_deref().unwrap_or_default());
+
+        // Retrieve from Vault
+        let vt_fut = vault::get_vault_properties(sdk_config, config.vault.as_ref(), verbosity, scope);
+
+        // Retrieve from Consul
+        let cs_fut = consul::get_consul_properties(config.consul.as_ref());
+
+        // Retrieve from etcd
+        let etcd_fut = etcd::get_etcd_properties(config_dir, config.etcd.as_ref());
+
+        // Retrieve from Doppler
+        let dop_fut = doppler::get_doppler_properties(config.doppler.as_deref().unwrap_or_default());
+
+        // Retrieve from HTTP endpoints
+        let http_fut = http::get_http_properties(config.http.as_deref().unwrap_or_default());
+
+        // Retrieve from Kubernetes
+        let k8s_fut = k8s::get_kubernetes_properties(config.kubernetes.as_deref().unwrap_or_default());
+
+        // Retrieve from Google Cloud Secret Manager
+        let gcp_fut = gcp::get_gcp_secret_properties(config.gcp_secrets.as_deref().unwrap_or_default());
+
+        // Retrieve from other accounts
+        let acct_fut = get_account_properties(sdk_config, config, watch);
+
+        let (az_res, s3_res, ddb_res, cfn_res, ac_res, ps_res, dps_res, param_res, hier_res, dsm_res, sm_res, cert_res, vt_res, cs_res, etcd_res, dop_res, http_res, k8s_res, gcp_res, acct_res) =
+            join!(az_fut, s3_fut, ddb_fut, cfn_fut, ac_fut, ps_fut, dps_fut, param_fut, hier_fut, dsm_fut, sm_fut, cert_fut, vt_fut, cs_fut, etcd_fut, dop_fut, http_fut, k8s_fut, gcp_fut, acct_fut);
+
+        let remote = RemoteData {
+            azure_blobs: normalize(key_formats.azure_blobs, &separators.azure_blobs, &prefixes.azure_blobs, az_res?),
+            s3_objects: normalize(key_formats.s3_objects, &separators.s3_objects, &prefixes.s3_objects, s3_res?),
+            dynamodb: normalize(key_formats.dynamodb, &separators.dynamodb, &prefixes.dynamodb, ddb_res?),
+            cloudformation_stacks: normalize(key_formats.cloudformation_stacks, &separators.cloudformation_stacks, &prefixes.cloudformation_stacks, cfn_res?),
+            appconfig: normalize(key_formats.appconfig, &separators.appconfig, &prefixes.appconfig, ac_res?),
+            parameter_store_prefixes: normalize(key_formats.parameter_store, &separators.parameter_store, &prefixes.parameter_store, ps_res?),
+            parameter_filters: normalize(key_formats.parameter_store, &separators.parameter_store, &prefixes.parameter_store, dps_res?),
+            parameters: normalize(key_formats.parameter_store, &separators.parameter_store, &prefixes.parameter_store, param_res?),
+            hierarchy: normalize(key_formats.parameter_store, &separators.parameter_store, &prefixes.parameter_store, hier_res?),
+            secret_prefixes: normalize(key_formats.secrets_manager, &separators.secrets_manager, &prefixes.secrets_manager, dsm_res?),
+            secrets: normalize(key_formats.secrets_manager, &separators.secrets_manager, &prefixes.secrets_manager, sm_res?),
+            certificates: normalize(key_formats.certificates, &separators.certificates, &prefixes.certificates, cert_res?),
+            vault: normalize(key_formats.vault, &separators.vault, &prefixes.vault, vt_res?),
+            consul: normalize(key_formats.consul, &separators.consul, &prefixes.consul, cs_res?),
+            etcd: normalize(key_formats.etcd, &separators.etcd, &prefixes.etcd, etcd_res?),
+            doppler: normalize(key_formats.doppler, &separators.doppler, &prefixes.doppler, dop_res?),
+            http: normalize(key_formats.http, &separators.http, &prefixes.http, http_res?),
+            kubernetes: normalize(key_formats.kubernetes, &separators.kubernetes, &prefixes.kubernetes, k8s_res?),
+            gcp_secrets: normalize(key_formats.gcp_secrets, &separators.gcp_secrets, &prefixes.gcp_secrets, gcp_res?),
+            accounts: normalize(key_formats.accounts, &separators.accounts, &prefixes.accounts, acct_res?),
+        };
+        if let Some(entry) = cache_entry {
+            entry.put(&remote)?;
+        }
+        Ok::<_, anyhow::Error>(remote)
+    };
+
+    // TODO Could probably use try_join! here... But how?
+    let (sops_res, exec_res, sql_res, plugin_res, remote_res) = join!(sops_fut, exec_fut, sql_fut, plugin_fut, remote_fut);
+
+    let sops_data = normalize(key_formats.sops, &separators.sops, &prefixes.sops, sops_res?);
+    let exec_data = normalize(key_formats.exec, &separators.exec, &prefixes.exec, exec_res?);
+    let sql_data = normalize(key_formats.sql, &separators.sql, &prefixes.sql, sql_res?);
+    let plugin_data = normalize(key_formats.plugins, &separators.plugins, &prefixes.plugins, plugin_res?);
+    let remote = remote_res?;
+
+    // Read the environment, last so it can override everything else
+    let env_data = normalize(key_formats.env, &separators.env, &prefixes.env, env::get_env_properties(config.env.as_ref())?);
+
+    if verbosity > 1 {
+        println!("file_data = {:#?}", file_data);
+        println!("sops_data = {:#?}", sops_data);
+        println!("az_data = {:#?}", remote.azure_blobs);
+        println!("s3_data = {:#?}", remote.s3_objects);
+        println!("ddb_data = {:#?}", remote.dynamodb);
+        println!("cfn_data = {:#?}", remote.cloudformation_stacks);
+        println!("ac_data = {:#?}", remote.appconfig);
+        println!("ps_data = {:#?}", remote.parameter_store_prefixes);
+        println!("dps_data = {:#?}", remote.parameter_filters);
+        println!("param_data = {:#?}", remote.parameters);
+        println!("hier_data = {:#?}", remote.hierarchy);
+        println!("dsm_data = {:#?}", remote.secret_prefixes);
+        println!("sm_data = {:#?}", remote.secrets);
+        println!("cert_data = {:#?}", remote.certificates);
+        println!("vt_data = {:#?}", remote.vault);
+        println!("cs_data = {:#?}", remote.consul);
+        println!("etcd_data = {:#?}", remote.etcd);
+        println!("dop_data = {:#?}", remote.doppler);
+        println!("exec_data = {:#?}", exec_data);
+        println!("http_data = {:#?}", remote.http);
+        println!("sql_data = {:#?}", sql_data);
+        println!("k8s_data = {:#?}", remote.kubernetes);
+        println!("gcp_data = {:#?}", remote.gcp_secrets);
+        println!("plugin_data = {:#?}", plugin_data);
+        println!("acct_data = {:#?}", remote.accounts);
+        println!("env_data = {:#?}", env_data);
+    }
+
+    // Merge results (later sources take precedence)
+    let (mut data, mut provenance) = merge_sources(vec![
+        ("files", file_data), ("sops", sops_data), ("azure_blobs", remote.azure_blobs), ("s3_objects", remote.s3_objects),
+        ("dynamodb", remote.dynamodb), ("cloudformation_stacks", remote.cloudformation_stacks), ("appconfig", remote.appconfig),
+        ("parameter_store_prefixes", remote.parameter_store_prefixes), ("parameter_filters", remote.parameter_filters), ("parameters", remote.parameters),
+        ("hierarchy", remote.hierarchy), ("secret_prefixes", remote.secret_prefixes), ("secrets", remote.secrets), ("certificates", remote.certificates),
+        ("vault", remote.vault), ("consul", remote.consul), ("etcd", remote.etcd), ("doppler", remote.doppler), ("exec", exec_data),
+        ("http", remote.http), ("sql", sql_data), ("kubernetes", remote.kubernetes), ("gcp_secrets", remote.gcp_secrets),
+        ("plugins", plugin_data), ("accounts", remote.accounts), ("env", env_data),
+    ], config.on_conflict.unwrap_or_default())?;
+
+    if let Some(rules) = &config.rename {
+        rename::apply(rules, &mut data);
+        rename::apply(rules, &mut provenance);
+    }
+
+    if let Some(path) = &config.override_file {
+        for (k, v) in files::get_override_properties(&config_dir.join(path))? {
+            provenance.insert(k.clone(), "override_file".to_owned());
+            data.insert(k, v);
+        }
+    }
+
+    if let Some(defaults) = &config.defaults {
+        let mut default_data = HashMap::new();
+        model::flatten_value("", defaults, &mut default_data);
+        for (k, v) in default_data {
+            if let Entry::Vacant(entry) = data.entry(k) {
+                provenance.insert(entry.key().clone(), "defaults".to_owned());
+                entry.insert(v);
+            }
+        }
+    }
+
+    kms::decrypt_values(sdk_config, config.kms.as_ref(), &mut data).await?;
+
+    if config.interpolate.unwrap_or(false) {
+        interpolate::apply(&mut data)?;
+    }
+
+    // Computed properties, which may refer to the merged ones
+    rds::add_rds_iam_tokens(sdk_config, config.rds_iam_tokens.as_deref().unwrap_or_default(), &mut data).await?;
+
+    if let Some(limits) = &config.limits {
+        limits::apply(limits, &mut data)?;
+    }
+
+    if explain {
+        let mut keys: Vec<&String> = data.keys().collect();
+        keys.sort();
+        for key in keys {
+            // Only the computed ones aren't from a source
+            println!("{} \u{2190} {}", key, provenance.get(key).map(String::as_str).unwrap_or("rds_iam_tokens"));
+        }
+    }
+
+    if verbosity > 0 { println!("data = {:#?}", data); }
+
+    Ok(data)
+}
+
+fn output_path(out: &Path, out_base: Option<&Path>, prefix: Option<&Path>) -> Result<PathBuf> {
+    let path = match out_base {
+        Some(base) if out.is_relative() => base.join(out),
+        _ => out.to_path_buf()
+    };
+
+    match prefix {
+        Some(prefix) => {
+            let absolute = std::env::current_dir()?.join(path);
+            let path = prefix.join(absolute.strip_prefix("/").unwrap_or(&absolute));
+            // Staging trees usually start out empty
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("Error creating directory {}", parent.display()))?;
+            }
+            Ok(path)
+        }
+        None => Ok(path)
+    }
+}
+
+fn template_path(config_dir: &Path, src: &Path) -> PathBuf {
+    if src.is_relative() {
+        // Relative to config base dir
+        let mut base = config_dir.to_path_buf();
+        base.push(src);
+        base
+    } else {
+        // Absolute path
+        src.to_path_buf()
+    }
+}
+
+fn template_engine() -> Handlebars<'static> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(no_escape);
+    handlebars.set_strict_mode(true);
+    handlebars
+}
+
+/// Each YAML document of the config file is a separate job.
+fn load_jobs(config_path: &Path) -> Result<Vec<Config>> {
+    let config_bytes = std::fs::read(config_path)
+        .with_context(|| format!("Error reading config {}", config_path.display()))?;
+    let jobs = serde_yaml::Deserializer::from_str(&String::from_utf8_lossy(&config_bytes))
+        .map(Config::deserialize)
+        .collect::<Result<Vec<Config>, _>>()
+        .with_context(|| format!("Error parsing config {}", config_path.display()))?;
+    if jobs.is_empty() {
+        bail!("Config {} is empty", config_path.display());
+    }
+    Ok(jobs)
+}
+
+fn lint(config_path: &Path) -> Result<()> {
+    let jobs = load_jobs(config_path)?;
+    let handlebars = template_engine();
+
+    let mut config_dir = config_path.canonicalize()?;
+    config_dir.pop();
+
+    let mut errors = 0;
+    for (config, ts) in jobs.iter().flat_map(|job| job.templates.iter().map(move |ts| (job, ts))) {
+        let schema = config.key_schema.as_deref();
+        let (name, problems) = match &ts.src {
+            Some(src) => {
+                let path = template_path(&config_dir, src);
+                let source = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Error reading template {}", path.display()))?;
+                (path.display().to_string(), lint::lint_template(&handlebars, &source, schema))
+            }
+            None => {
+                // Generated outputs only reference their keys/root
+                let mut problems = Vec::new();
+                if let Some(schema) = schema {
+                    for key in ts.keys.iter().flat_map(|k| k.values()).chain(ts.root.as_ref()) {
+                        if !lint::in_schema(schema, key) {
+                            problems.push(lint::Problem::error(format!("key {} is not in the key schema", key)));
+                        }
+                    }
+                }
+                (ts.out.display().to_string(), problems)
+            }
+        };
+
+        for problem in &problems {
+            println!("{}: {}", name, problem);
+            if problem.severity == lint::Severity::Error {
+                errors += 1;
+            }
+        }
+    }
+
+    if errors > 0 {
+        bail!("{} error(s) found", errors);
+    }
+    Ok(())
+}
+
+/// Each directory under tests/ is a case: a model.yaml fixture (as the model
+/// would be after merging) and the expected outputs, named after the file name
+/// of each template's `out`. Templates without one are skipped.
+fn test(config_path: &Path, verbosity: u8) -> Result<()> {
+    let jobs = load_jobs(config_path)?;
+    let handlebars = template_engine();
+
+    let mut config_dir = config_path.canonicalize()?;
+    config_dir.pop();
+    let tests_dir = config_dir.join(TESTS_DIR);
+
+    let mut cases: Vec<PathBuf> = std::fs::read_dir(&tests_dir)
+        .with_context(|| format!("Error reading {}", tests_dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    cases.retain(|p| p.is_dir());
+    cases.sort();
+
+    let (mut passed, mut failed) = (0, 0);
+    for case in &cases {
+        let fixture = case.join(TEST_MODEL);
+        let document: Value = serde_yaml::from_slice(&std::fs::read(&fixture)
+            .with_context(|| format!("Error reading {}", fixture.display()))?)
+            .with_context(|| format!("Error parsing {}", fixture.display()))?;
+        // Round trip through properties, so values are strings as they'd be for real
+        let mut data = HashMap::new();
+        model::flatten_value("", &document, &mut data);
+
+        for job in &jobs {
+            let model = model::build_template_model(data.clone(), job.coerce_types.unwrap_or(false));
+            for ts in &job.templates {
+                if !should_render(ts, &model, verbosity)? {
+                    continue;
+                }
+
+                let expected_path = match ts.out.file_name() {
+                    Some(name) => case.join(name),
+                    None => continue
+                };
+                let expected = match std::fs::read(&expected_path) {
+                    Ok(expected) => expected,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e).with_context(|| format!("Error reading {}", expected_path.display()))
+                };
+
+                let mut result: Vec<u8> = Vec::new();
+                let name = expected_path.strip_prefix(&tests_dir).unwrap_or(&expected_path).display().to_string();
+                match render(&handlebars, &config_dir, ts, &model, verbosity, &mut result) {
+                    Ok(()) if result == expected => {
+                        println!("ok      {}", name);
+                        passed += 1;
+                    }
+                    Ok(()) => {
+                        println!("FAILED  {}", name);
+                        let (expected, result) = (String::from_utf8_lossy(&expected), String::from_utf8_lossy(&result));
+                        print!("{}", similar::TextDiff::from_lines(&expected, &result)
+                            .unified_diff()
+                            .header("expected", "rendered"));
+                        failed += 1;
+                    }
+                    Err(e) => {
+                        println!("FAILED  {}: {:#}", name, e);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        bail!("{} test(s) failed", failed);
+    }
+    Ok(())
+}
+
+fn should_render(ts: &TemplateSpec, model: &Value, verbosity: u8) -> Result<bool> {
+    let when = match &ts.when {
+        Some(when) => when,
+        None => return Ok(true)
+    };
+    let result = condition::evaluate(when, model)
+        .with_context(|| format!("Error evaluating condition for {}", ts.out.display()))?;
+    if !result && verbosity > 0 { println!("Skipping {} ({} is false)", ts.out.display(), when); }
+    Ok(result)
+}
+
+fn render<W: Write>(handlebars: &Handlebars, config_dir: &Path, ts: &TemplateSpec, model: &Value, verbosity: u8, out: &mut W) -> Result<()> {
+    match (ts.format, &ts.src) {
+        (Some(format), _) => {
+            if verbosity > 0 { println!("Generating {}...", ts.out.display()); }
+
+            let result = generate::generate(format, ts.keys.as_ref(), ts.root.as_deref(), model)
+                .with_context(|| format!("Error generating {}", ts.out.display()))?;
+            out.write_all(&result)
+                .with_context(|| format!("Error writing {}", ts.out.display()))?;
+        }
+        (None, Some(src)) => {
+            let template_path = template_path(config_dir, src);
+
+            if verbosity > 0 { println!("Rendering template {}...", template_path.display()); }
+
+            let mut template_file = File::open(&template_path)
+                .with_context(|| format!("Error reading template {}", template_path.display()))?;
+
+            handlebars.render_template_source_to_write(&mut template_file, model, out)
+                .with_context(|| format!("Error rendering template {}", template_path.display()))?;
+        }
+        (None, None) => bail!("Template for {} has neither src nor format", ts.out.display())
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timed out")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Runs the future to completion, unless the deadline (if any) passes first,
+/// in which case it's dropped (cancelling any requests in flight).
+fn block_on_until<F: Future>(rt: &Runtime, deadline: Option<Instant>, fut: F) -> Result<F::Output> {
+    rt.block_on(async {
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, fut).await.map_err(|_| TimedOut.into()),
+            None => Ok(fut.await)
+        }
+    })
+}
+
+/// Retrieves all properties, within the fetch timeout if it's sooner, the
+/// remote sources' from the cache if they're in it. When watching, the
+/// state is what was fetched last time round.
+#[allow(clippy::too_many_arguments)]
+fn fetch(opt: &Opt, config: &Config, config_dir: &Path, rt: &Runtime, sdk_config: &SdkConfig, deadline: Option<Instant>,
+         cache_entry: Option<cache::Entry>, watch: Option<&watch::State>) -> Result<HashMap<String, String>> {
+    if config.uses_aws() {
+        let profile = opt.profile.as_deref().or(config.profile.as_deref());
+        block_on_until(rt, deadline, credentials::check(sdk_config, profile))??;
+    }
+
+    let fetch_deadline = config.timeouts.as_ref().and_then(|t| t.fetch).map(|ms| {
+        let _guard = rt.enter();
+        Instant::now() + Duration::from_millis(ms)
+    });
+    let fetch_deadline = deadline.into_iter().chain(fetch_deadline).min();
+    block_on_until(rt, fetch_deadline, get_properties(sdk_config, config, config_dir, opt.verbose, opt.explain, cache_entry.as_ref(), watch))?
+}
+
+/// The model keys a template refers to, or None if it could be any of them.
+fn template_keys(handlebars: &Handlebars, config_dir: &Path, ts: &TemplateSpec) -> Option<Vec<String>> {
+    let mut keys = match (ts.format, &ts.src) {
+        (Some(_), _) => match (&ts.root, &ts.keys) {
+            (Some(root), _) => vec![root.clone()],
+            (None, Some(keys)) => keys.values().cloned().collect(),
+            (None, None) => return None
+        },
+        (None, Some(src)) => {
+            let source = std::fs::read_to_string(template_path(config_dir, src)).ok()?;
+            lint::references(handlebars, &source)?
+        }
+        (None, None) => return None
+    };
+    keys.extend(ts.when.as_deref().map(|when| condition::key(when).to_owned()));
+    Some(keys)
+}
+
+/// The templates to render again when watching: those affected by the keys
+/// that changed since the last run, or whose source was modified since.
+fn watched_templates(config: &Config, config_dir: &Path, previous: &HashMap<String, String>, since: SystemTime,
+                     data: &HashMap<String, String>) -> HashSet<usize> {
+    let changed = watch::changed_keys(previous, data);
+    let handlebars = template_engine();
+
+    config.templates.iter().enumerate()
+        .filter(|(_, ts)| {
+            let modified = ts.src.as_ref().is_some_and(|src| std::fs::metadata(template_path(config_dir, src))
+                .and_then(|meta| meta.modified())
+                .map_or(true, |modified| modified >= since));
+            modified || (!changed.is_empty() && watch::affected(&changed, template_keys(&handlebars, config_dir, ts).as_deref()))
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Renders the templates (only these, if given), returning the outputs that changed.
+fn run(opt: &Opt, config: &Config, config_dir: &Path, rt: &Runtime, deadline: Option<Instant>, data: HashMap<String, String>,
+       only: Option<&HashSet<usize>>) -> Result<Vec<String>> {
+
+    // Generate (JSON) template model
+    let model = model::build_template_model(data, config.coerce_types.unwrap_or(false));
+    if opt.verbose > 1 { println!("model = {:#?}", model); }
+
+    // Initialize template engine
+    let handlebars = template_engine();
+
+    if let Some(schema) = &config.schema {
+        schema::validate(schema, config_dir, &model)?;
+    }
+
+    let out_base = config.out_base.as_ref().map(|base| config_dir.join(base));
+    let temp_dir = config.temp_dir.as_ref().map(|dir| config_dir.join(dir));
+
+    let names: Vec<String> = config.templates.iter().map(TemplateSpec::name).collect();
+    let after: Vec<&[String]> = config.templates.iter().map(|ts| ts.after.as_deref().unwrap_or_default()).collect();
+    let order = order::order(&names, &after)?;
+
+    let outputs = Outputs {
+        opt,
+        rt,
+        deadline,
+        handlebars: &handlebars,
+        config_dir,
+        model: &model,
+        out_base: out_base.as_deref(),
+        temp_dir: temp_dir.as_deref(),
+        backups: if opt.nobackup { 0 } else { config.backups.unwrap_or(DEFAULT_BACKUPS) },
+    };
+
+    // Render the templates
+    let mut changed = Vec::new();
+    let mut errors = Vec::new();
+    for ts in order.into_iter().filter(|i| only.is_none_or(|only| only.contains(i))).map(|i| &config.templates[i]) {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(TimedOut.into());
+        }
+
+        match outputs.output(ts) {
+            Ok(Some(out)) => changed.push(out),
+            Ok(None) => (),
+            Err(e) if opt.keep_going && !e.is::<TimedOut>() => errors.push(format!("{}: {:#}", ts.out.display(), e)),
+            Err(e) => return Err(e)
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!("{} template(s) failed:\n  {}", errors.len(), errors.join("\n  "));
+    }
+
+    Ok(changed)
+}
+
+/// Everything needed to render & write out a template.
+struct Outputs<'a> {
+    opt: &'a Opt,
+    rt: &'a Runtime,
+    deadline: Option<Instant>,
+    handlebars: &'a Handlebars<'a>,
+    config_dir: &'a Path,
+    model: &'a Value,
+    out_base: Option<&'a Path>,
+    temp_dir: Option<&'a Path>,
+    /// Generations of backups to keep
+    backups: usize,
+}
+
+impl Outputs<'_> {
+    /// Returns the output, if it changed.
+    fn output(&self, ts: &TemplateSpec) -> Result<Option<String>> {
+        let opt = self.opt;
+
+        if !should_render(ts, self.model, opt.verbose)? {
+            return Ok(None);
+        }
+
+        let k8s_target = k8s::parse_target(&ts.out)?;
+
+        if k8s_target.is_some() || push::is_http_target(&ts.out) {
+            let mut result: Vec<u8> = Vec::new();
+            render(self.handlebars, self.config_dir, ts, self.model, opt.verbose, &mut result)?;
+
+            if !opt.dryrun {
+                let is_changed = match k8s_target {
+                    Some(target) => block_on_until(self.rt, self.deadline, k8s::output(&target, &result, opt.verbose))??,
+                    None => block_on_until(self.rt, self.deadline, push::output(&ts.out, ts.http.as_ref(), &result, opt.verbose))??
+                };
+                if is_changed {
+                    return Ok(Some(ts.out.display().to_string()));
+                }
+            }
+        } else if opt.dryrun {
+            render(self.handlebars, self.config_dir, ts, self.model, opt.verbose, &mut std::io::sink())?;
+        } else {
+            // Stream straight to a temporary file next to the destination
+            let out = output_path(&ts.out, self.out_base, opt.prefix.as_deref())?;
+            let mut staged = output::StagedOutput::new(&out, self.temp_dir, opt.verbose)?;
+            render(self.handlebars, self.config_dir, ts, self.model, opt.verbose, &mut staged)?;
+            if staged.commit(self.backups, opt.verify, opt.verbose)? {
+                return Ok(Some(out.display().to_string()));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Role, MFA device, external ID & session name.
+type MfaRoleKey = (String, String, Option<String>, Option<String>);
+
+/// The SDK config for a job: its profile, region, retry policy & role.
+/// Base configs are only loaded once per profile (and proxy).
+fn job_sdk_config(opt: &Opt, config: &Config, rt: &Runtime, deadline: Option<Instant>,
+                  base_sdk_configs: &mut HashMap<(Option<String>, Option<proxy::ProxySpec>), SdkConfig>,
+                  mfa_credentials: &mut HashMap<MfaRoleKey, Credentials>) -> Result<SdkConfig> {
+    // Determine profile. Priority: command line > config file > environment
+    let profile = opt.profile.clone().or_else(|| config.profile.clone());
+    let key = (profile, config.proxy.clone());
+    let base_sdk_config = match base_sdk_configs.get(&key) {
+        Some(sdk_config) => sdk_config.clone(),
+        None => {
+            let mut loader = aws_config::from_env();
+            if let Some(profile) = &key.0 {
+                loader = loader.profile_name(profile);
+            }
+            // Set while loading, so credential providers use it too
+            let http_client = proxy::http_client(key.1.as_ref())?;
+            let fixtures = match (&opt.record, &opt.replay) {
+                (Some(dir), _) => Some(fixtures::Mode::Record(dir.clone())),
+                (_, Some(dir)) => Some(fixtures::Mode::Replay(dir.clone())),
+                _ => None
+            };
+            if let Some(fixtures::Mode::Replay(_)) = fixtures {
+                // Requests must still be signed, but with anything
+                loader = loader.credentials_provider(Credentials::new("replay", "replay", None, None, "psmerge-replay"));
+            }
+            match (fixtures, http_client) {
+                (Some(mode), http_client) => loader = loader.http_client(fixtures::http_client(&mode, http_client)),
+                (None, Some(http_client)) => loader = loader.http_client(http_client),
+                (None, None) => ()
+            }
+            let sdk_config = block_on_until(rt, deadline, loader.load())?;
+            base_sdk_configs.insert(key, sdk_config.clone());
+            sdk_config
+        }
+    };
+
+    // Determine region. Priority: command line > config file > environment > profile
+    let region = match opt.region.clone() {
+        Some(region_str) => Some(Region::new(region_str)),
+        _ => config.region.clone().map(Region::new)
+    };
+
+    // Switch to new region, if needed
+    let sdk_config = match region {
+        Some(region) => base_sdk_config.clone().into_builder().region(region).build(),
+        _ => base_sdk_config.clone()
+    };
+
+    let mut builder = sdk_config.into_builder();
+    if let Some(use_fips) = config.use_fips {
+        builder.set_use_fips(Some(use_fips));
+    }
+    if let Some(use_dual_stack) = config.use_dual_stack {
+        builder.set_use_dual_stack(Some(use_dual_stack));
+    }
+    let sdk_config = builder.build();
+
+    let sdk_config = match &config.retry {
+        Some(spec) => retry::configure(&sdk_config, spec),
+        None => sdk_config
+    };
+    let sdk_config = match &config.timeouts {
+        Some(spec) => timeouts::configure(&sdk_config, spec),
+        None => sdk_config
+    };
+
+    // And role. Priority: command line > config file
+    let external_id = opt.external_id.as_deref().or(config.external_id.as_deref());
+    let session_name = opt.session_name.as_deref().or(config.session_name.as_deref());
+    let sdk_config = match (opt.role_arn.as_ref().or(config.role_arn.as_ref()), opt.mfa_serial.as_ref().or(config.mfa_serial.as_ref())) {
+        (Some(role_arn), Some(mfa_serial)) => {
+            // A code can only be used once, so jobs assuming the same role share its credentials
+            let key = (role_arn.clone(), mfa_serial.clone(), external_id.map(str::to_owned), session_name.map(str::to_owned));
+            let credentials = match mfa_credentials.get(&key) {
+                Some(credentials) => credentials.clone(),
+                None => {
+                    let token_code = match &opt.mfa_token {
+                        Some(code) => code.clone(),
+                        None => accounts::prompt_mfa_code(mfa_serial)?
+                    };
+                    let credentials = block_on_until(rt, deadline, accounts::assume_role_with_mfa(&sdk_config, role_arn,
+                        external_id, session_name, mfa_serial, &token_code))??;
+                    mfa_credentials.insert(key, credentials.clone());
+                    credentials
+                }
+            };
+            sdk_config.to_builder().credentials_provider(SharedCredentialsProvider::new(credentials)).build()
+        }
+        (Some(role_arn), None) => rt.block_on(accounts::assume_role(&sdk_config, role_arn, external_id, session_name)),
+        (None, _) => sdk_config
+    };
+
+    Ok(sdk_config)
+}
+
+fn main() -> Result<()> {
+    // Parse command line args
+    let opt = Opt::parse();
+
+    match &opt.command {
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(*shell, &mut Opt::command(), env!("CARGO_PKG_NAME"), &mut std::io::stdout());
+            return Ok(());
+        }
+        Some(Command::Lint { config }) => return lint(config),
+        Some(Command::Test { config }) => return test(config, opt.verbose),
+        Some(Command::History { path }) => return history::history(path),
+        Some(Command::Restore { generation, path }) => return output::restore(path, *generation),
+        Some(Command::Snapshot { .. }) | None => ()
+    }
+
+    // Snapshots are taken like a normal run, but stop short of rendering
+    let (config_path, mut snapshot) = match &opt.command {
+        Some(Command::Snapshot { config, .. }) => (config.as_path(), Some(snapshot::Snapshot::new())),
+        _ => (opt.config.as_deref().expect("config is required without a subcommand"), None)
+    };
+    let offline = opt.offline.as_deref().map(snapshot::read).transpose()?;
+    if offline.is_some() && opt.explain {
+        eprintln!("WARNING: Nothing to explain with --offline, snapshots only hold the merged properties");
+    }
+
+    // Base directory of config file (for relative templates & files)
+    let mut config_dir = config_path.canonicalize()
+        .with_context(|| format!("Error reading config {}", config_path.display()))?;
+    config_dir.pop();
+
+    // Parse config file
+    let mut jobs = load_jobs(config_path)?;
+    for name in &opt.jobs {
+        if !jobs.iter().any(|job| job.name.as_ref() == Some(name)) {
+            bail!("Job {} not found in {}", name, config_path.display());
+        }
+    }
+    if !opt.jobs.is_empty() {
+        jobs.retain(|job| job.name.as_ref().is_some_and(|name| opt.jobs.contains(name)));
+    }
+    for config in &mut jobs {
+        if !opt.facts.is_empty() {
+            config.facts.get_or_insert_with(BTreeMap::new).extend(opt.facts.iter().cloned());
+        }
+        if opt.fetch_concurrency.is_some() {
+            config.fetch_concurrency = opt.fetch_concurrency;
+        }
+        if opt.on_conflict.is_some() {
+            config.on_conflict = opt.on_conflict;
+        }
+        if let Some(path) = &opt.override_file {
+            config.override_file = Some(std::path::absolute(path)?);
+        }
+        if let Some(url) = &opt.endpoint_url {
+            config.endpoints = Some(EndpointsSpec { ssm: Some(url.clone()), secretsmanager: Some(url.clone()) });
+        }
+        if opt.strict_sources {
+            for secret in config.secrets.iter_mut().flatten() {
+                secret.required.get_or_insert(true);
+            }
+            for parameter in config.parameters.iter_mut().flatten() {
+                parameter.required.get_or_insert(true);
+            }
+        }
+    }
+
+    // Only need to selectively go async
+    let rt = Runtime::new().unwrap();
+    let cache = match &opt.cache {
+        Some(dir) => Some(cache::Cache::open(dir, Duration::from_secs(opt.cache_ttl))?),
+        None => None
+    };
+    // Anything in the config (or on the command line) could change what's fetched
+    let config_bytes = std::fs::read(config_path)
+        .with_context(|| format!("Error reading config {}", config_path.display()))?;
+    // As could the environment the AWS config comes from
+    let aws_env: Vec<Option<String>> = CACHE_KEY_ENV.iter().map(|var| std::env::var(var).ok()).collect();
+    let fetch_opts = format!("{:?}", (&opt.region, &opt.profile, &opt.role_arn, &opt.external_id,
+        &opt.session_name, &opt.endpoint_url, &opt.facts, &opt.on_conflict, aws_env));
+
+    let mut base_sdk_configs: HashMap<(Option<String>, Option<proxy::ProxySpec>), SdkConfig> = HashMap::new();
+    let mut mfa_credentials = HashMap::new();
+
+    // Privileges are dropped once every job has fetched, so they have to agree
+    let run_as = jobs.first().and_then(|job| job.run_as.as_ref());
+    if jobs.iter().any(|job| job.run_as.as_ref() != run_as) {
+        bail!("Jobs must all have the same run_as (or none), as it applies to all of them");
+    }
+    if run_as.is_some() && opt.watch.is_some() {
+        bail!("--watch can't be used with run_as, as fetching again after dropping privileges would fail");
+    }
+
+    // When watching, each job's properties as of its last successful run
+    let mut previous: Vec<Option<(HashMap<String, String>, SystemTime)>> = jobs.iter().map(|_| None).collect();
+    // And what each fetched, so unchanged versions aren't fetched again
+    let states: Vec<watch::State> = jobs.iter().map(|_| watch::State::default()).collect();
+
+    loop {
+        // Each run gets the timeout when watching
+        let deadline = opt.timeout.map(|secs| {
+            let _guard = rt.enter();
+            Instant::now() + Duration::from_secs(secs)
+        });
+        let started = SystemTime::now();
+
+        // Fetch for every job first, while still privileged
+        let mut fetched = Vec::new();
+        for (i, config) in jobs.iter().enumerate() {
+            let job_name = config.name.clone().unwrap_or_else(|| format!("#{}", i + 1));
+            if jobs.len() > 1 && opt.verbose > 0 { println!("Fetching for job {}...", job_name); }
+
+            let sdk_config = match offline {
+                // Nothing to connect to
+                Some(_) => Ok(SdkConfig::builder().build()),
+                None => job_sdk_config(&opt, config, &rt, deadline, &mut base_sdk_configs, &mut mfa_credentials)
+            };
+            // A bad profile, proxy or role only fails this job
+            let (sdk_config, sdk_config_error) = match sdk_config {
+                Ok(sdk_config) => (sdk_config, None),
+                Err(e) => (SdkConfig::builder().build(), Some(e))
+            };
+
+            let data = match (&offline, sdk_config_error) {
+                (_, Some(e)) => Err(e),
+                (Some(offline), None) => offline.get(&job_name).cloned()
+                    .ok_or_else(|| anyhow!("Job {} not in snapshot", job_name))
+                    .and_then(|mut data| {
+                        // The snapshot's data is final, so the override goes on top
+                        if let Some(path) = &config.override_file {
+                            data.extend(files::get_override_properties(&config_dir.join(path))?);
+                        }
+                        Ok(data)
+                    }),
+                (None, None) => {
+                    let cache_entry = cache.as_ref()
+                        .map(|cache| cache.entry(&[&config_bytes, job_name.as_bytes(), fetch_opts.as_bytes()]));
+                    fetch(&opt, config, &config_dir, &rt, &sdk_config, deadline, cache_entry, opt.watch.and(Some(&states[i])))
+                }
+            };
+            fetched.push((job_name, sdk_config, data));
+        }
+
+        if let (Some(run_as), None) = (run_as, &snapshot) {
+            privileges::drop_privileges(run_as, opt.verbose)?;
+        }
+
+        let mut failed = 0;
+        for (i, (config, (job_name, sdk_config, data))) in jobs.iter().zip(fetched).enumerate() {
+            let only = match (&data, &previous[i]) {
+                (Ok(data), Some((previous, since))) => Some(watched_templates(config, &config_dir, previous, *since, data)),
+                _ => None
+            };
+            if only.as_ref().is_some_and(HashSet::is_empty) {
+                if opt.verbose > 0 { println!("Nothing changed for job {}", job_name); }
+                continue;
+            }
+            if jobs.len() > 1 && opt.verbose > 0 { println!("Running job {}...", job_name); }
+
+            let watched = opt.watch.and(data.as_ref().ok().cloned());
+            let result = data.and_then(|data| match &mut snapshot {
+                Some(snapshot) => {
+                    snapshot.insert(job_name.clone(), data);
+                    Ok(Vec::new())
+                }
+                None => run(&opt, config, &config_dir, &rt, deadline, data, only.as_ref())
+            });
+            // Anything that failed is rendered in full next time round
+            previous[i] = watched.filter(|_| result.is_ok()).map(|data| (data, started));
+
+            // Only for real runs
+            let report = !opt.dryrun && offline.is_none() && snapshot.is_none();
+
+            // Bounded separately, so a run that timed out still reports it
+            let report_deadline = {
+                let _guard = rt.enter();
+                Some(Instant::now() + REPORT_TIMEOUT)
+            };
+
+            if let Some(notifications) = &config.notifications {
+                if report && block_on_until(&rt, report_deadline, notify::notify(&sdk_config, notifications, config_path, &result)).is_err() {
+                    eprintln!("WARNING: Sending notifications timed out");
+                }
+            }
+
+            if let Some(metrics) = &config.metrics {
+                if report && block_on_until(&rt, report_deadline, metrics::publish(&sdk_config, metrics, config_path, &result)).is_err() {
+                    eprintln!("WARNING: Publishing metrics timed out");
+                }
+            }
+
+            if let Err(e) = result {
+                if opt.watch.is_some() {
+                    // Tried again next time round
+                    match jobs.len() {
+                        1 => eprintln!("Error: {:?}", e),
+                        _ => eprintln!("Error: job {}: {:?}", job_name, e)
+                    }
+                    continue;
+                }
+                if e.is::<TimedOut>() {
+                    eprintln!("Error: {:?}", e);
+                    std::process::exit(EXIT_TIMEOUT);
+                }
+                if jobs.len() == 1 {
+                    return Err(e);
+                }
+                // Other jobs are independent, so carry on
+                eprintln!("Error: job {}: {:?}", job_name, e);
+                failed += 1;
+            }
+        }
+
+        match opt.watch {
+            Some(secs) => std::thread::sleep(Duration::from_secs(secs)),
+            None if failed > 0 => bail!("{} of {} jobs failed", failed, jobs.len()),
+            None => break
+        }
+    }
+
+    if let (Some(snapshot), Some(Command::Snapshot { encrypt, out, .. })) = (&snapshot, &opt.command) {
+        snapshot::write(out, snapshot, *encrypt)?;
+        if opt.verbose > 0 { println!("Snapshot written to {}", out.display()); }
+    }
+    Ok(())
+}
+
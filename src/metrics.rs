@@ -0,0 +1,47 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use aws_config::SdkConfig;
+use aws_sdk_cloudwatch::types::{Dimension, MetricDatum, StandardUnit};
+use serde::Deserialize;
+
+const DEFAULT_NAMESPACE: &str = "psmerge";
+
+#[derive(Debug, Deserialize)]
+pub struct MetricsConfig {
+    /// Defaults to psmerge.
+    namespace: Option<String>,
+}
+
+/// Publishes Renders (always 1), Changed (outputs) and Errors (0 or 1) for
+/// a run, dimensioned by host and config. Problems publishing are only
+/// warned about.
+pub async fn publish(sdk_config: &SdkConfig, config: &MetricsConfig, config_path: &Path, result: &Result<Vec<String>>) {
+    let host = hostname::get().map(|h| h.to_string_lossy().into_owned()).unwrap_or_default();
+    let dimensions = vec![
+        Dimension::builder().name("Host").value(host).build(),
+        Dimension::builder().name("Config").value(config_path.display().to_string()).build(),
+    ];
+
+    let (changed, errors) = match result {
+        Ok(changed) => (changed.len(), 0),
+        Err(_) => (0, 1),
+    };
+    let datum = |name: &str, value: usize| MetricDatum::builder()
+        .metric_name(name)
+        .set_dimensions(Some(dimensions.clone()))
+        .value(value as f64)
+        .unit(StandardUnit::Count)
+        .build();
+
+    let namespace = config.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE);
+    let result = aws_sdk_cloudwatch::Client::new(sdk_config).put_metric_data()
+        .namespace(namespace)
+        .metric_data(datum("Renders", 1))
+        .metric_data(datum("Changed", changed))
+        .metric_data(datum("Errors", errors))
+        .send().await;
+    if let Err(e) = result.with_context(|| format!("Error publishing metrics to {}", namespace)) {
+        eprintln!("WARNING: {:#}", e);
+    }
+}
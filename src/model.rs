@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use serde_json::{Value, Map};
+use serde_json::{Value, Map, Number};
 
 fn insert_with_path(object: &mut Value, path: &[&str], key_pos: usize, value: &str) {
     match object.as_object_mut() {
@@ -25,7 +25,76 @@ fn insert_with_path(object: &mut Value, path: &[&str], key_pos: usize, value: &s
     }
 }
 
-pub fn build_template_model(data: HashMap<String, String>) -> Value {
+/// Flattens a structured document into dotted property keys, e.g. `{"db": {"host": "x"}}`
+/// becomes `db.host = x`. Array elements are keyed by their index and nulls are dropped.
+pub fn flatten_value(prefix: &str, value: &Value, data: &mut HashMap<String, String>) {
+    let child_key = |k: &str| if prefix.is_empty() { k.to_owned() } else { format!("{}.{}", prefix, k) };
+
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map {
+                flatten_value(&child_key(k), v, data);
+            }
+        }
+        Value::Array(values) => {
+            for (i, v) in values.iter().enumerate() {
+                flatten_value(&child_key(&i.to_string()), v, data);
+            }
+        }
+        Value::String(s) => { data.insert(prefix.to_owned(), s.clone()); }
+        Value::Null => (),
+        other => { data.insert(prefix.to_owned(), other.to_string()); }
+    }
+}
+
+/// Turns objects keyed 0, 1, 2... (e.g. from `servers.0.host` & `servers.1.host`)
+/// into arrays, so templates can iterate them with `{{#each}}`. Objects with
+/// gaps in their indexes are left as they are.
+fn arrays_from_indexes(value: &mut Value) {
+    if let Value::Object(map) = value {
+        for v in map.values_mut() {
+            arrays_from_indexes(v);
+        }
+
+        let is_list = !map.is_empty()
+            && (0..map.len()).all(|i| map.contains_key(&i.to_string()));
+        if is_list {
+            let items = (0..map.len()).map(|i| map.remove(&i.to_string()).unwrap()).collect();
+            *value = Value::Array(items);
+        }
+    }
+}
+
+/// Converts "true" & "false" into booleans and numbers into numbers, where
+/// nothing is lost (e.g. not "007" or "1.50").
+fn coerce_types(value: &mut Value) {
+    match value {
+        Value::Object(map) => map.values_mut().for_each(coerce_types),
+        Value::Array(items) => items.iter_mut().for_each(coerce_types),
+        Value::String(s) => match s.as_str() {
+            "true" => *value = Value::Bool(true),
+            "false" => *value = Value::Bool(false),
+            _ => if let Ok(n) = s.parse::<Number>() {
+                if n.to_string() == *s {
+                    *value = Value::Number(n);
+                }
+            }
+        },
+        _ => ()
+    }
+}
+
+/// Returns the value at the given dotted path of the model, if any.
+pub fn lookup<'a>(model: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(model, |v, part| match v {
+        Value::Array(items) => items.get(part.parse::<usize>().ok()?),
+        _ => v.get(part)
+    })
+}
+
+/// Builds the nested model from the dotted keys, with native booleans &
+/// numbers if `coerce` is set.
+pub fn build_template_model(data: HashMap<String, String>, coerce: bool) -> Value {
     let mut sorted_keys: Vec<String> = data.keys().cloned().collect();
     sorted_keys.sort(); // Maybe should have been a BTreeMap?
 
@@ -37,5 +106,9 @@ pub fn build_template_model(data: HashMap<String, String>) -> Value {
         insert_with_path(&mut result, &parts, 0, value);
     }
 
+    arrays_from_indexes(&mut result);
+    if coerce {
+        coerce_types(&mut result);
+    }
     result
 }
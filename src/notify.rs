@@ -0,0 +1,102 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use aws_config::SdkConfig;
+use handlebars::{Handlebars, no_escape};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+const DEFAULT_MESSAGE: &str = "psmerge on {{host}} ({{config}}) \
+    {{#if error}}failed: {{error}}\
+    {{else}}changed {{#each changed}}{{#unless @first}}, {{/unless}}{{this}}{{/each}}{{/if}}";
+
+fn default_true() -> bool { true }
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationConfig {
+    /// Notify when any output changed.
+    #[serde(default = "default_true")]
+    on_change: bool,
+    /// Notify when the run failed.
+    #[serde(default = "default_true")]
+    on_failure: bool,
+    /// Handlebars template for the message, given `host`, `config`,
+    /// `changed` (list of outputs) and `error`.
+    message: Option<String>,
+    sns_topic_arn: Option<String>,
+    /// Generic webhook, POSTed the message and its context as JSON.
+    webhook_url: Option<String>,
+    /// Environment variable holding a Slack incoming webhook URL.
+    slack_webhook_env: Option<String>,
+}
+
+async fn post_json(url: &str, body: &Value) -> Result<()> {
+    Client::new().post(url)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(body)?)
+        .send().await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn send(sdk_config: &SdkConfig, config: &NotificationConfig, context: &Value) {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(no_escape);
+    let message = match handlebars.render_template(config.message.as_deref().unwrap_or(DEFAULT_MESSAGE), context) {
+        Ok(message) => message,
+        Err(e) => {
+            eprintln!("WARNING: Error rendering notification message: {}", e);
+            return;
+        }
+    };
+
+    if let Some(topic) = &config.sns_topic_arn {
+        let result = aws_sdk_sns::Client::new(sdk_config).publish()
+            .topic_arn(topic)
+            .subject("psmerge")
+            .message(&message)
+            .send().await;
+        if let Err(e) = result.with_context(|| format!("Error publishing to {}", topic)) {
+            eprintln!("WARNING: {:#}", e);
+        }
+    }
+
+    if let Some(url) = &config.webhook_url {
+        let mut body = context.clone();
+        body["message"] = Value::String(message.clone());
+        if let Err(e) = post_json(url, &body).await.with_context(|| format!("Error calling webhook {}", url)) {
+            eprintln!("WARNING: {:#}", e);
+        }
+    }
+
+    if let Some(var) = &config.slack_webhook_env {
+        let result = match std::env::var(var) {
+            Ok(url) => post_json(&url, &json!({ "text": message })).await.context("Error posting to Slack"),
+            Err(_) => Err(anyhow::anyhow!("Environment variable {} not set", var))
+        };
+        if let Err(e) = result {
+            eprintln!("WARNING: {:#}", e);
+        }
+    }
+}
+
+/// Sends notifications for the outcome of a run (the outputs that changed,
+/// or the error). Problems sending them are only warned about.
+pub async fn notify(sdk_config: &SdkConfig, config: &NotificationConfig, config_path: &Path, result: &Result<Vec<String>>) {
+    let (changed, error) = match result {
+        Ok(changed) if config.on_change && !changed.is_empty() => (changed.as_slice(), None),
+        Err(e) if config.on_failure => (&[][..], Some(format!("{:#}", e))),
+        _ => return
+    };
+
+    let host = hostname::get().map(|h| h.to_string_lossy().into_owned()).unwrap_or_default();
+    let context = json!({
+        "host": host,
+        "config": config_path.display().to_string(),
+        "changed": changed,
+        "error": error,
+    });
+
+    send(sdk_config, config, &context).await;
+}
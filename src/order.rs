@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+/// Orders items so each comes after its dependencies, otherwise keeping
+/// their original order. Returns indices into `names`.
+pub fn order(names: &[String], after: &[&[String]]) -> Result<Vec<usize>> {
+    let mut index = HashMap::new();
+    for (i, name) in names.iter().enumerate() {
+        if index.insert(name.as_str(), i).is_some() {
+            bail!("Template name {} is not unique", name);
+        }
+    }
+
+    let mut remaining: Vec<usize> = vec![0; names.len()];
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); names.len()];
+    for (i, deps) in after.iter().enumerate() {
+        for dep in deps.iter() {
+            match index.get(dep.as_str()) {
+                Some(&j) => {
+                    remaining[i] += 1;
+                    dependents[j].push(i);
+                }
+                None => bail!("Template {} is after unknown template {}", names[i], dep)
+            }
+        }
+    }
+
+    let mut done = vec![false; names.len()];
+    let mut result = Vec::with_capacity(names.len());
+    // Always take the first ready item, so unrelated ones keep their order
+    while let Some(i) = (0..names.len()).find(|&i| !done[i] && remaining[i] == 0) {
+        done[i] = true;
+        result.push(i);
+        for &d in &dependents[i] {
+            remaining[d] -= 1;
+        }
+    }
+
+    if result.len() < names.len() {
+        let cycle: Vec<&str> = (0..names.len()).filter(|&i| !done[i]).map(|i| names[i].as_str()).collect();
+        bail!("Templates {} depend on each other in a cycle", cycle.join(", "));
+    }
+
+    Ok(result)
+}
@@ -1,12 +1,18 @@
-use std::path::Path;
-use std::io::prelude::*;
-use std::fs::{File, rename, write};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::io::{prelude::*, BufWriter, ErrorKind};
+use std::fs::{File, FileTimes, Metadata, OpenOptions, copy, metadata, read_link, remove_file, rename, set_permissions};
+use std::time::SystemTime;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 
 const BUFFER_SIZE: usize = 10240;
 const BACKUP_SUFFIX: &str = "~";
+const MANIFEST_SUFFIX: &str = "~.json";
+// As Linux's MAXSYMLINKS
+const MAX_SYMLINKS: usize = 40;
 
 type MyHash = Sha256;
 
@@ -37,40 +43,333 @@ fn hash_file<D: Digest + Default>(path: &Path) -> Result<Option<Vec<u8>>> {
     Ok(hash)
 }
 
-fn backup_file(path: &Path) -> Result<()> {
-    if let Ok(f) = File::open(path) {
-        drop(f); // FIXME Does this actually close the file?
+/// The backup of a file, 1 being the latest (`~`), then `~2`, `~3`, etc.
+pub fn backup_path(path: &Path, generation: usize) -> PathBuf {
+    let mut backup_path = OsString::from(path.as_os_str());
+    backup_path.push(BACKUP_SUFFIX);
+    if generation > 1 {
+        backup_path.push(generation.to_string());
+    }
+    PathBuf::from(backup_path)
+}
+
+/// How many backups of a file there are, counting until one is missing.
+pub fn backup_count(path: &Path) -> usize {
+    (1..).take_while(|&generation| backup_path(path, generation).symlink_metadata().is_ok()).count()
+}
+
+fn manifest_path(path: &Path) -> PathBuf {
+    let mut manifest_path = OsString::from(path.as_os_str());
+    manifest_path.push(MANIFEST_SUFFIX);
+    PathBuf::from(manifest_path)
+}
+
+/// What a backed up file was like, so `psmerge restore` can reinstate it
+/// exactly. The manifest alongside the file (with the `~.json` suffix) is a
+/// list of them, latest first.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    pub backed_up: SystemTime,
+    pub modified: SystemTime,
+    pub accessed: SystemTime,
+    /// Unix permissions, owner & group.
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
 
-        // FIXME This is pretty shady. Possible to do entirely in Path/PathBuf?
-        let mut backup_path: String = (*path.to_str().unwrap()).to_owned();
-        backup_path.push_str(BACKUP_SUFFIX);
+impl Backup {
+    fn new(meta: &Metadata) -> Result<Backup> {
+        #[cfg(unix)]
+        let (mode, uid, gid) = {
+            use std::os::unix::fs::MetadataExt;
 
-        rename(path, backup_path)?;
+            (Some(meta.mode() & 0o7777), Some(meta.uid()), Some(meta.gid()))
+        };
+        #[cfg(not(unix))]
+        let (mode, uid, gid) = (None, None, None);
+
+        Ok(Backup {
+            backed_up: SystemTime::now(),
+            modified: meta.modified()?,
+            accessed: meta.accessed()?,
+            mode,
+            uid,
+            gid,
+        })
     }
-    // TODO Possible to check if error is because it doesn't exist?
+}
 
+/// The backups of a file, latest first (none without a manifest).
+pub fn read_manifest(path: &Path) -> Result<Vec<Backup>> {
+    let manifest_path = manifest_path(path);
+    match std::fs::read(&manifest_path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| format!("Error parsing {}", manifest_path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Error reading {}", manifest_path.display()))
+    }
+}
+
+fn write_manifest(path: &Path, backups: &[Backup]) -> Result<()> {
+    let manifest_path = manifest_path(path);
+    let temp_path = temp_path(manifest_path.parent().unwrap_or(Path::new(".")), &manifest_path)?;
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(backups)?)
+        .and_then(|_| rename(&temp_path, &manifest_path))
+        .with_context(|| format!("Error writing {}", manifest_path.display()))
+}
+
+/// Applies the recorded mode, timestamps & (where permitted) ownership.
+fn apply_metadata(file: &File, path: &Path, backup: &Backup) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Some(mode) = backup.mode {
+            file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+        }
+        if let Err(e) = std::os::unix::fs::fchown(file, backup.uid, backup.gid) {
+            eprintln!("WARNING: Unable to preserve ownership of {}: {}", path.display(), e);
+        }
+    }
+    file.set_times(FileTimes::new()
+        .set_accessed(backup.accessed)
+        .set_modified(backup.modified))?;
     Ok(())
 }
 
-pub fn output(path: &Path, contents: &[u8], nobackup: bool, verbosity: u8) -> Result<()> {
-    if let Some(hash) = hash_file::<MyHash>(path).with_context(|| format!("Error hashing file {}", path.display()))? {
-        // Hash contents
-        let mut hasher = MyHash::new();
-        hasher.update(contents);
-        let content_hash = &hasher.finalize()[..];
+/// Copies the file to its (latest) backup path, carrying over its mode,
+/// timestamps and (where permitted) ownership, which are also recorded in
+/// its manifest. Earlier backups move back a generation, keeping `keep` in
+/// all. The original is left in place, to be atomically replaced afterwards.
+fn backup_file(path: &Path, keep: usize) -> Result<()> {
+    let meta = match metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into())
+    };
+
+    let count = backup_count(path);
+    let mut manifest = read_manifest(path)?;
+    // Backups made without a manifest have no entries, at the end
+    manifest.truncate(count);
+
+    // Those about to fall off the end (even the latest, which as a
+    // read-only file couldn't be copied over)
+    for generation in (keep..=count).rev() {
+        remove_file(backup_path(path, generation))?;
+    }
+    for generation in (1..keep.min(count + 1)).rev() {
+        rename(backup_path(path, generation), backup_path(path, generation + 1))?;
+    }
+
+    // Also copies the permissions
+    let backup_path = backup_path(path, 1);
+    copy(path, &backup_path)?;
+
+    let backup = Backup::new(&meta)?;
+    apply_metadata(&File::open(&backup_path)?, &backup_path, &backup)?;
+    manifest.insert(0, backup);
+    manifest.truncate(keep);
+    write_manifest(path, &manifest)
+}
+
+/// Puts a generation of a file's backups back in its place, with the mode,
+/// timestamps & ownership recorded when it was backed up (or the backup's
+/// own, if it has no manifest).
+pub fn restore(path: &Path, generation: usize) -> Result<()> {
+    if generation == 0 {
+        bail!("Backup generations start at 1");
+    }
+    let path = &resolve_symlinks(path)?;
+    let backup_path = backup_path(path, generation);
+    let meta = metadata(&backup_path)
+        .with_context(|| format!("Error reading backup {}", backup_path.display()))?;
+    let backup = match read_manifest(path)?.into_iter().nth(generation.saturating_sub(1)) {
+        Some(backup) => backup,
+        None => Backup::new(&meta)?
+    };
 
-        // If unchanged, do nothing
-        if *content_hash == hash[..] {
-            if verbosity > 0 { println!("File {} unchanged", path.display()); }
-            return Ok(());
+    // Staged, so the file is replaced atomically
+    let temp_path = temp_path(path.parent().unwrap_or(Path::new(".")), path)?;
+    let result = copy(&backup_path, &temp_path)
+        .with_context(|| format!("Error copying backup {}", backup_path.display()))
+        .and_then(|_| apply_metadata(&File::open(&temp_path)?, path, &backup))
+        .and_then(|_| rename(&temp_path, path).with_context(|| format!("Error writing file {}", path.display())));
+    if result.is_err() {
+        let _ = remove_file(&temp_path);
+    }
+    result
+}
+
+/// The file a symlinked destination (eventually) points to, which is what
+/// gets replaced rather than the link.
+fn resolve_symlinks(path: &Path) -> Result<PathBuf> {
+    let mut path = path.to_owned();
+    for _ in 0..MAX_SYMLINKS {
+        match path.symlink_metadata() {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let target = read_link(&path)
+                    .with_context(|| format!("Error reading symbolic link {}", path.display()))?;
+                path = match path.parent() {
+                    Some(parent) => parent.join(target),
+                    None => target
+                };
+            }
+            _ => return Ok(path)
         }
     }
+    bail!("Too many levels of symbolic links at {}", path.display())
+}
+
+/// Temporary file name for the destination, in the given directory.
+fn temp_path(dir: &Path, path: &Path) -> Result<PathBuf> {
+    let file_name = path.file_name()
+        .ok_or_else(|| anyhow!("Output {} is not a file", path.display()))?;
+
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    Ok(dir.join(temp_name))
+}
+
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
 
-    if !nobackup {
-        backup_file(path)
-            .with_context(|| format!("Error backing up file {}", path.display()))?;
+    match (metadata(a), metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false
     }
+}
 
-    write(path, contents)?;
-    Ok(())
+#[cfg(not(unix))]
+fn same_filesystem(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Output staged in a temporary file and hashed as it's written. The
+/// destination is only replaced on commit; if dropped before then, the
+/// temporary file is removed.
+pub struct StagedOutput {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: Option<BufWriter<File>>,
+    hasher: MyHash,
+}
+
+impl StagedOutput {
+    /// Stages in `temp_dir` if it's on the same filesystem as the
+    /// destination, otherwise alongside the destination.
+    pub fn new(path: &Path, temp_dir: Option<&Path>, verbosity: u8) -> Result<StagedOutput> {
+        let path = &resolve_symlinks(path)?;
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new(".")
+        };
+
+        // The final rename must stay on one filesystem to be atomic
+        let temp_path = match temp_dir {
+            Some(temp_dir) if same_filesystem(temp_dir, dir) => temp_path(temp_dir, path)?,
+            Some(temp_dir) => {
+                if verbosity > 0 {
+                    println!("{} is on a different filesystem from {}, staging alongside it", temp_dir.display(), path.display());
+                }
+                temp_path(dir, path)?
+            }
+            None => temp_path(dir, path)?
+        };
+
+        let file = OpenOptions::new().write(true).create_new(true).open(&temp_path)
+            .with_context(|| format!("Error creating temporary file {}", temp_path.display()))?;
+
+        Ok(StagedOutput {
+            path: path.to_owned(),
+            temp_path,
+            file: Some(BufWriter::new(file)),
+            hasher: MyHash::new(),
+        })
+    }
+
+    /// Replaces the destination if the content differs, returning whether it
+    /// did. `backups` generations of the destination are kept (if any).
+    pub fn commit(mut self, backups: usize, verify: bool, verbosity: u8) -> Result<bool> {
+        let path = self.path.clone();
+
+        self.file.take().unwrap().into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|f| f.sync_all())
+            .with_context(|| format!("Error writing temporary file {}", self.temp_path.display()))?;
+
+        let content_hash = std::mem::take(&mut self.hasher).finalize();
+
+        if let Some(hash) = hash_file::<MyHash>(&path).with_context(|| format!("Error hashing file {}", path.display()))? {
+            // If unchanged, do nothing
+            if content_hash[..] == hash[..] {
+                if verbosity > 0 { println!("File {} unchanged", path.display()); }
+                return Ok(false);
+            }
+
+            // Keep the existing file's permissions & (where permitted) ownership
+            let meta = metadata(&path)?;
+            set_permissions(&self.temp_path, meta.permissions())
+                .with_context(|| format!("Error setting permissions of {}", self.temp_path.display()))?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+
+                if let Err(e) = std::os::unix::fs::chown(&self.temp_path, Some(meta.uid()), Some(meta.gid())) {
+                    eprintln!("WARNING: Unable to preserve ownership of {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        if backups > 0 {
+            backup_file(&path, backups)
+                .with_context(|| format!("Error backing up file {}", path.display()))?;
+        }
+
+        match rename(&self.temp_path, &path) {
+            // Same device but different mounts (e.g. bind mounts), so restage alongside
+            Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+                let alongside = temp_path(path.parent().unwrap_or(Path::new(".")), &path)?;
+                copy(&self.temp_path, &alongside)
+                    .with_context(|| format!("Error creating temporary file {}", alongside.display()))?;
+                let _ = remove_file(&self.temp_path);
+                self.temp_path = alongside;
+                rename(&self.temp_path, &path)
+            }
+            result => result
+        }.with_context(|| format!("Error writing file {}", path.display()))?;
+
+        if verify {
+            let hash = hash_file::<MyHash>(&path)
+                .with_context(|| format!("Error hashing file {}", path.display()))?;
+            if hash.as_deref() != Some(&content_hash[..]) {
+                bail!("Verification of {} failed: contents on disk differ from those written", path.display());
+            }
+            if verbosity > 0 { println!("File {} verified", path.display()); }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Write for StagedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.file.as_mut().unwrap().write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for StagedOutput {
+    fn drop(&mut self) {
+        // Once committed, this is either gone or an unchanged duplicate
+        let _ = remove_file(&self.temp_path);
+    }
 }
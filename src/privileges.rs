@@ -0,0 +1,50 @@
+use anyhow::Result;
+use serde::Deserialize;
+
+/// User (and optionally group) to switch to, once everything is fetched,
+/// when started as root.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct RunAs {
+    user: String,
+    /// Defaults to the user's primary group.
+    group: Option<String>,
+}
+
+#[cfg(unix)]
+pub fn drop_privileges(run_as: &RunAs, verbosity: u8) -> Result<()> {
+    use std::ffi::CString;
+
+    use anyhow::{anyhow, Context};
+    use nix::unistd::{initgroups, setgid, setuid, Group, Uid, User};
+
+    if !Uid::effective().is_root() {
+        eprintln!("WARNING: Not running as root, so not switching to user {}", run_as.user);
+        return Ok(());
+    }
+
+    let user = User::from_name(&run_as.user)
+        .with_context(|| format!("Error looking up user {}", run_as.user))?
+        .ok_or_else(|| anyhow!("User {} not found", run_as.user))?;
+    let gid = match &run_as.group {
+        Some(group) => Group::from_name(group)
+            .with_context(|| format!("Error looking up group {}", group))?
+            .ok_or_else(|| anyhow!("Group {} not found", group))?
+            .gid,
+        None => user.gid
+    };
+
+    // Groups first, as that's no longer permitted once the user changes
+    initgroups(&CString::new(user.name.as_str())?, gid)
+        .and_then(|_| setgid(gid))
+        .and_then(|_| setuid(user.uid))
+        .with_context(|| format!("Error switching to user {}", run_as.user))?;
+
+    if verbosity > 0 { println!("Switched to user {} (uid {}, gid {})", run_as.user, user.uid, gid); }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(run_as: &RunAs, _verbosity: u8) -> Result<()> {
+    anyhow::bail!("Switching to user {} is only supported on Unix", run_as.user)
+}
@@ -0,0 +1,44 @@
+use anyhow::{Context, Result};
+use aws_smithy_http_client::{Builder, Connector, proxy::ProxyConfig, tls};
+use aws_smithy_http_client::tls::rustls_provider::CryptoMode;
+use aws_sdk_ssm::config::SharedHttpClient;
+use serde::Deserialize;
+
+const PROXY_VARS: &[&str] = &["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"];
+
+/// Proxy for AWS requests, instead of the one from the environment.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub struct ProxySpec {
+    url: String,
+    /// Comma-separated hosts & domains to connect to directly.
+    no_proxy: Option<String>,
+}
+
+/// HTTP client for the SDK that goes through the proxy, or through the one
+/// in HTTPS_PROXY & co. (honouring NO_PROXY) if none is given. None if
+/// there's neither, leaving the SDK's default client.
+pub fn http_client(spec: Option<&ProxySpec>) -> Result<Option<SharedHttpClient>> {
+    let proxy_config = match spec {
+        Some(spec) => {
+            let proxy_config = ProxyConfig::all(spec.url.as_str())
+                .with_context(|| format!("Invalid proxy URL {}", spec.url))?;
+            match &spec.no_proxy {
+                Some(no_proxy) => proxy_config.no_proxy(no_proxy),
+                None => proxy_config
+            }
+        }
+        None if PROXY_VARS.iter().any(|var| std::env::var_os(var).is_some()) => ProxyConfig::from_env(),
+        None => return Ok(None)
+    };
+
+    Ok(Some(Builder::new().build_with_connector_fn(move |settings, components| {
+        let mut builder = Connector::builder();
+        builder.set_connector_settings(settings.cloned());
+        if let Some(components) = components {
+            builder.set_sleep_impl(components.sleep_impl());
+        }
+        builder.proxy_config(proxy_config.clone())
+            .tls_provider(tls::Provider::Rustls(CryptoMode::AwsLc))
+            .build()
+    })))
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Client, Method};
+use serde::Deserialize;
+use sha2::{Sha256, Digest};
+
+const DEFAULT_METHOD: &str = "PUT";
+
+/// Request options for templates whose `out` is an HTTP(S) URL.
+#[derive(Debug, Default, Deserialize)]
+pub struct HttpSpec {
+    /// PUT (default) or POST.
+    method: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// Environment variable holding a bearer token.
+    token_env: Option<String>,
+}
+
+pub fn is_http_target(path: &Path) -> bool {
+    match path.to_str() {
+        Some(s) => s.starts_with("http://") || s.starts_with("https://"),
+        None => false
+    }
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Endpoints can't be hashed like files, so the hash of the last content
+/// successfully pushed to each one is remembered locally.
+fn state_path(method: &str, url: &str) -> Result<PathBuf> {
+    let base = match (std::env::var_os("XDG_STATE_HOME"), std::env::var_os("HOME")) {
+        (Some(state), _) => PathBuf::from(state),
+        (None, Some(home)) => Path::new(&home).join(".local/state"),
+        (None, None) => return Err(anyhow!("Neither XDG_STATE_HOME nor HOME set"))
+    };
+    Ok(base.join("psmerge/http").join(hex_digest(format!("{} {}", method, url).as_bytes())))
+}
+
+/// Returns whether the content was pushed.
+pub async fn output(url: &Path, spec: Option<&HttpSpec>, contents: &[u8], verbosity: u8) -> Result<bool> {
+    let default_spec = HttpSpec::default();
+    let spec = spec.unwrap_or(&default_spec);
+    let url = url.to_str().unwrap();
+
+    let method = spec.method.as_deref().unwrap_or(DEFAULT_METHOD).to_uppercase();
+    let method = Method::from_bytes(method.as_bytes())
+        .with_context(|| format!("Invalid HTTP method {}", method))?;
+
+    let state = state_path(method.as_str(), url)?;
+    let content_hash = hex_digest(contents);
+
+    // If unchanged since the last push, do nothing
+    if std::fs::read_to_string(&state).ok().as_deref() == Some(content_hash.as_str()) {
+        if verbosity > 0 { println!("{} {} unchanged", method, url); }
+        return Ok(false);
+    }
+
+    let mut request = Client::new().request(method.clone(), url);
+    for (name, value) in &spec.headers {
+        request = request.header(name, value);
+    }
+    if let Some(var) = &spec.token_env {
+        let token = std::env::var(var).with_context(|| format!("Environment variable {} not set", var))?;
+        request = request.bearer_auth(token);
+    }
+
+    request.body(contents.to_vec())
+        .send().await
+        .and_then(|r| r.error_for_status())
+        .with_context(|| format!("Error sending {} {}", method, url))?;
+
+    if let Some(dir) = state.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Error creating state directory {}", dir.display()))?;
+    }
+    std::fs::write(&state, content_hash)
+        .with_context(|| format!("Error writing state file {}", state.display()))?;
+
+    Ok(true)
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use aws_config::SdkConfig;
+use aws_credential_types::provider::ProvideCredentials;
+use aws_sigv4::http_request::{sign, SignableBody, SignableRequest, SignatureLocation, SigningSettings};
+use aws_sigv4::sign::v4;
+use reqwest::Url;
+use serde::Deserialize;
+
+// Maximum lifetime RDS accepts for an authentication token
+const TOKEN_LIFETIME: Duration = Duration::from_secs(900);
+
+/// An RDS IAM authentication token to generate. Each of hostname, port &
+/// username is given either literally or as the name of another property.
+#[derive(Debug, Deserialize)]
+pub struct TokenSpec {
+    /// Property key the token is stored under.
+    key: String,
+    hostname: Option<String>,
+    hostname_key: Option<String>,
+    port: Option<u16>,
+    port_key: Option<String>,
+    username: Option<String>,
+    username_key: Option<String>,
+    /// Region of the database, if different from the global one.
+    region: Option<String>,
+}
+
+fn resolve(data: &HashMap<String, String>, literal: Option<String>, key: &Option<String>, what: &str) -> Result<String> {
+    match (literal, key) {
+        (Some(value), _) => Ok(value),
+        (None, Some(key)) => data.get(key).cloned()
+            .ok_or_else(|| anyhow!("Property {} (for {}) not defined", key, what)),
+        (None, None) => Err(anyhow!("Neither {} nor {}_key given", what, what))
+    }
+}
+
+async fn generate_token(config: &SdkConfig, spec: &TokenSpec, data: &HashMap<String, String>) -> Result<String> {
+    let hostname = resolve(data, spec.hostname.clone(), &spec.hostname_key, "hostname")?;
+    let port = resolve(data, spec.port.map(|p| p.to_string()), &spec.port_key, "port")?;
+    let username = resolve(data, spec.username.clone(), &spec.username_key, "username")?;
+
+    let region = match &spec.region {
+        Some(region) => region.clone(),
+        None => config.region().ok_or_else(|| anyhow!("No region configured"))?.to_string()
+    };
+
+    let credentials = config.credentials_provider()
+        .ok_or_else(|| anyhow!("No credentials provider configured"))?
+        .provide_credentials().await?;
+    let identity = credentials.into();
+
+    let mut settings = SigningSettings::default();
+    settings.expires_in = Some(TOKEN_LIFETIME);
+    settings.signature_location = SignatureLocation::QueryParams;
+
+    let params = v4::SigningParams::builder()
+        .identity(&identity)
+        .region(&region)
+        .name("rds-db")
+        .time(SystemTime::now())
+        .settings(settings)
+        .build()?
+        .into();
+
+    let mut url = Url::parse(&format!("https://{}:{}/", hostname, port))?;
+    url.query_pairs_mut()
+        .append_pair("Action", "connect")
+        .append_pair("DBUser", &username);
+
+    let signable = SignableRequest::new("GET", url.as_str(), std::iter::empty(), SignableBody::Bytes(&[]))?;
+    let (instructions, _signature) = sign(signable, &params)?.into_parts();
+    {
+        let mut query = url.query_pairs_mut();
+        for (name, value) in instructions.params() {
+            query.append_pair(name, value);
+        }
+    }
+
+    // The token is the presigned URL minus its scheme
+    Ok(url.as_str().trim_start_matches("https://").to_owned())
+}
+
+/// Generates the configured tokens, adding them to the (already merged) properties.
+pub async fn add_rds_iam_tokens(config: &SdkConfig, specs: &[TokenSpec], data: &mut HashMap<String, String>) -> Result<()> {
+    for spec in specs {
+        let token = generate_token(config, spec, data).await
+            .with_context(|| format!("Failed to generate RDS IAM token {}", spec.key))?;
+        data.insert(spec.key.clone(), token);
+    }
+
+    Ok(())
+}
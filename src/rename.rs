@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use serde_yaml::Mapping;
+
+#[derive(Debug)]
+enum Pattern {
+    Exact(String),
+    Regex(Regex),
+}
+
+/// Renames keys after fetching, e.g. a legacy `DB_HOST` to `db.host`. Each
+/// rule is matched exactly, or as a regex when written `/pattern/` (matching
+/// the whole key, its captures usable as `$1` etc.). The first matching rule
+/// wins.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "Mapping")]
+pub struct RenameRules {
+    rules: Vec<(Pattern, String)>,
+}
+
+impl TryFrom<Mapping> for RenameRules {
+    type Error = anyhow::Error;
+
+    fn try_from(mapping: Mapping) -> Result<Self> {
+        let mut rules = Vec::new();
+
+        for (from, to) in mapping {
+            let from = from.as_str().ok_or_else(|| anyhow!("rename keys must be strings"))?;
+            let to = to.as_str().ok_or_else(|| anyhow!("rename of {} must be a string", from))?;
+            let pattern = match from.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+                Some(re) => Pattern::Regex(Regex::new(&format!("^(?:{})$", re))
+                    .with_context(|| format!("Invalid rename pattern {}", from))?),
+                None => Pattern::Exact(from.to_owned())
+            };
+            rules.push((pattern, to.to_owned()));
+        }
+
+        Ok(RenameRules { rules })
+    }
+}
+
+impl RenameRules {
+    fn rename(&self, key: &str) -> Option<String> {
+        self.rules.iter().find_map(|(pattern, to)| match pattern {
+            Pattern::Exact(from) => (from == key).then(|| to.clone()),
+            Pattern::Regex(re) => re.is_match(key).then(|| re.replace(key, to.as_str()).into_owned()),
+        })
+    }
+}
+
+pub fn apply(rules: &RenameRules, data: &mut HashMap<String, String>) {
+    let mut renamed: Vec<(String, String)> = data.keys()
+        .filter_map(|k| rules.rename(k).map(|new_key| (k.clone(), new_key)))
+        .collect();
+    renamed.sort();
+
+    let values: Vec<(String, String, String)> = renamed.into_iter()
+        .map(|(k, new_key)| { let v = data.remove(&k).unwrap(); (k, new_key, v) })
+        .collect();
+    for (k, new_key, v) in values {
+        if data.insert(new_key.clone(), v).is_some() {
+            eprintln!("WARNING: {} renamed to {}, replacing its existing value", k, new_key);
+        }
+    }
+}
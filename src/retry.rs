@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use aws_config::SdkConfig;
+use aws_config::retry::RetryConfig;
+use aws_sdk_ssm::error::ProvideErrorMetadata;
+use serde::Deserialize;
+
+/// Further attempts once the SDK has given up on a throttled request.
+pub const THROTTLE_RETRIES: u32 = 4;
+const THROTTLE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    #[default]
+    Standard,
+    /// Also rate limits on the client side when throttled.
+    Adaptive,
+}
+
+/// Retry policy for AWS requests, in place of the SDK's defaults.
+#[derive(Debug, Deserialize)]
+pub struct RetrySpec {
+    #[serde(default)]
+    mode: Mode,
+    /// Including the first.
+    max_attempts: Option<u32>,
+    /// In milliseconds.
+    initial_backoff: Option<u64>,
+    /// In milliseconds.
+    max_backoff: Option<u64>,
+}
+
+pub fn configure(sdk_config: &SdkConfig, spec: &RetrySpec) -> SdkConfig {
+    let mut retry_config = match spec.mode {
+        Mode::Standard => RetryConfig::standard(),
+        Mode::Adaptive => RetryConfig::adaptive(),
+    };
+    if let Some(attempts) = spec.max_attempts {
+        retry_config = retry_config.with_max_attempts(attempts);
+    }
+    if let Some(ms) = spec.initial_backoff {
+        retry_config = retry_config.with_initial_backoff(Duration::from_millis(ms));
+    }
+    if let Some(ms) = spec.max_backoff {
+        retry_config = retry_config.with_max_backoff(Duration::from_millis(ms));
+    }
+
+    sdk_config.to_builder().retry_config(retry_config).build()
+}
+
+pub fn is_throttling<E: ProvideErrorMetadata>(e: &E) -> bool {
+    matches!(e.code(), Some("ThrottlingException" | "Throttling" | "TooManyRequestsException"))
+}
+
+/// Exponential, with jitter so that many hosts throttled together don't
+/// all come back at once.
+pub fn backoff(attempt: u32) -> Duration {
+    let base = THROTTLE_DELAY * 2u32.pow(attempt.saturating_sub(1));
+    let jitter = getrandom::u32().unwrap_or(0) % 1000;
+    base + Duration::from_millis(jitter.into())
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use aws_config::SdkConfig;
+use serde_json::Value;
+
+use crate::model::flatten_value;
+
+const S3_SCHEME: &str = "s3://";
+
+/// Splits an `s3://bucket/key` URI.
+fn parse_uri(uri: &str) -> Result<(&str, &str)> {
+    uri.strip_prefix(S3_SCHEME)
+        .and_then(|rest| rest.split_once('/'))
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+        .ok_or_else(|| anyhow!("Invalid S3 URI {}, expected s3://bucket/key", uri))
+}
+
+pub async fn get_s3_properties(config: &SdkConfig, uris: &[String]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    if uris.is_empty() {
+        return Ok(data);
+    }
+
+    let client = aws_sdk_s3::Client::new(config);
+
+    for uri in uris {
+        let (bucket, key) = parse_uri(uri)?;
+
+        let object = client.get_object()
+            .bucket(bucket)
+            .key(key)
+            .send().await
+            .with_context(|| format!("Failed to get {}", uri))?;
+        let body = object.body.collect().await
+            .with_context(|| format!("Failed to read {}", uri))?
+            .into_bytes();
+
+        // JSON is also valid YAML
+        let doc: Value = serde_yaml::from_slice(&body)
+            .with_context(|| format!("Error parsing {}", uri))?;
+
+        flatten_value("", &doc, &mut data);
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::model;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    Object,
+    /// An object keyed by index, as arrays are flattened.
+    Array,
+}
+
+/// What the merged model must look like, checked before rendering.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum SchemaConfig {
+    /// JSON Schema (as JSON or YAML), relative to the config file.
+    File(PathBuf),
+    /// Required keys & their types.
+    Keys(BTreeMap<String, KeyType>),
+}
+
+fn check_type(value: &Value, key_type: KeyType) -> bool {
+    match (key_type, value) {
+        (KeyType::String, Value::String(_)) => true,
+        (KeyType::Integer, Value::String(s)) => s.parse::<i64>().is_ok(),
+        (KeyType::Number, Value::String(s)) => s.parse::<f64>().is_ok(),
+        (KeyType::Boolean, Value::String(s)) => s == "true" || s == "false",
+        // With coerce_types
+        (KeyType::Integer, Value::Number(n)) => n.is_i64() || n.is_u64(),
+        (KeyType::Number, Value::Number(_)) => true,
+        (KeyType::Boolean, Value::Bool(_)) => true,
+        (KeyType::Object, Value::Object(_)) => true,
+        (KeyType::Array, Value::Array(_)) => true,
+        // With gaps in its indexes
+        (KeyType::Array, Value::Object(m)) => m.keys().all(|k| k.parse::<usize>().is_ok()),
+        _ => false
+    }
+}
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("{:?}", s),
+        Value::Object(_) => "an object".to_owned(),
+        Value::Array(_) => "an array".to_owned(),
+        other => other.to_string(),
+    }
+}
+
+fn check_keys(keys: &BTreeMap<String, KeyType>, model: &Value) -> Vec<String> {
+    keys.iter().filter_map(|(key, key_type)| match model::lookup(model, key) {
+        None => Some(format!("{}: missing", key)),
+        Some(value) if !check_type(value, *key_type) =>
+            Some(format!("{}: expected {}, got {}", key, format!("{:?}", key_type).to_lowercase(), describe(value))),
+        Some(_) => None
+    }).collect()
+}
+
+fn check_json_schema(path: &Path, model: &Value) -> Result<Vec<String>> {
+    let schema: Value = serde_yaml::from_slice(&std::fs::read(path)
+        .with_context(|| format!("Error reading schema {}", path.display()))?)
+        .with_context(|| format!("Error parsing schema {}", path.display()))?;
+    let validator = jsonschema::validator_for(&schema)
+        .map_err(|e| anyhow!("Invalid schema {}: {}", path.display(), e))?;
+
+    Ok(validator.iter_errors(model)
+        .map(|e| format!("{}: {}", e.instance_path, e))
+        .collect())
+}
+
+pub fn validate(schema: &SchemaConfig, config_dir: &Path, model: &Value) -> Result<()> {
+    let errors = match schema {
+        SchemaConfig::File(path) => check_json_schema(&config_dir.join(path), model)?,
+        SchemaConfig::Keys(keys) => check_keys(keys, model),
+    };
+
+    if !errors.is_empty() {
+        bail!("Model doesn't match the schema:\n  {}", errors.join("\n  "));
+    }
+    Ok(())
+}
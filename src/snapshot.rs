@@ -0,0 +1,49 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+
+use age::secrecy::SecretString;
+use anyhow::{anyhow, Context, Result};
+
+use crate::agekeys;
+use crate::cache::create_private;
+
+/// Passphrase encrypted snapshots are encrypted with (as an age passphrase).
+pub const KEY_ENV: &str = "PSMERGE_SNAPSHOT_KEY";
+/// Marks an encrypted snapshot, as opposed to plain JSON.
+const MAGIC: &[u8] = b"age-encryption.org/v1\n";
+
+/// Fetched properties of each job, by job name.
+pub type Snapshot = BTreeMap<String, HashMap<String, String>>;
+
+fn passphrase() -> Result<SecretString> {
+    std::env::var(KEY_ENV).map(SecretString::from)
+        .map_err(|_| anyhow!("{} must be set for encrypted snapshots", KEY_ENV))
+}
+
+pub fn write(path: &Path, snapshot: &Snapshot, encrypted: bool) -> Result<()> {
+    let json = serde_json::to_vec_pretty(snapshot)?;
+    let contents = if encrypted {
+        age::encrypt(&age::scrypt::Recipient::new(passphrase()?), &json)
+            .with_context(|| format!("Error encrypting snapshot {}", path.display()))?
+    } else {
+        json
+    };
+
+    // Holds secrets either way
+    create_private(path).and_then(|mut f| f.write_all(&contents))
+        .with_context(|| format!("Error writing snapshot {}", path.display()))
+}
+
+pub fn read(path: &Path) -> Result<Snapshot> {
+    let contents = std::fs::read(path)
+        .with_context(|| format!("Error reading snapshot {}", path.display()))?;
+    let json = if contents.starts_with(MAGIC) {
+        let identities: Vec<Box<dyn age::Identity>> = vec![Box::new(age::scrypt::Identity::new(passphrase()?))];
+        agekeys::decrypt(&identities, &contents)
+            .with_context(|| format!("Error decrypting snapshot {}", path.display()))?
+    } else {
+        contents
+    };
+    serde_json::from_slice(&json).with_context(|| format!("Error parsing snapshot {}", path.display()))
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use aes_gcm::aead::consts::U32;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::aes::Aes256;
+use aes_gcm::{AesGcm, Key, Nonce};
+use anyhow::{anyhow, bail, Context, Result};
+use aws_config::{Region, SdkConfig};
+use aws_sdk_kms::primitives::Blob;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::Deserialize;
+use serde_yaml::Value;
+use sha2::{Digest, Sha512};
+
+use crate::agekeys;
+use crate::keyformat::KeyPrefix;
+use crate::model::flatten_value;
+
+const METADATA_KEY: &str = "sops";
+const ENCRYPTED_PREFIX: &str = "ENC[AES256_GCM,";
+
+/// sops uses 32-byte IVs
+type SopsCipher = AesGcm<Aes256, U32>;
+
+/// A sops-encrypted JSON or YAML file, decrypted & flattened into dotted keys.
+#[derive(Debug, Deserialize)]
+pub struct SopsSpec {
+    /// Relative to the config file.
+    path: PathBuf,
+    /// age identities, if the file is encrypted for age. Defaults to
+    /// $SOPS_AGE_KEY_FILE.
+    age_identities: Option<PathBuf>,
+    /// Prefix for its keys.
+    key: Option<String>,
+}
+
+impl KeyPrefix for SopsSpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    #[serde(default)]
+    kms: Vec<KmsKey>,
+    #[serde(default)]
+    age: Vec<AgeKey>,
+    lastmodified: String,
+    /// Encrypted hash of the values, authenticated with lastmodified.
+    mac: String,
+    #[serde(default)]
+    mac_only_encrypted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct KmsKey {
+    arn: String,
+    enc: String,
+    context: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AgeKey {
+    enc: String,
+}
+
+async fn kms_data_key(sdk_config: &SdkConfig, key: &KmsKey) -> Result<Vec<u8>> {
+    // arn:aws:kms:<region>:...
+    let sdk_config = match key.arn.split(':').nth(3) {
+        Some(region) if !region.is_empty() => sdk_config.to_builder().region(Region::new(region.to_owned())).build(),
+        _ => sdk_config.clone()
+    };
+    let client = aws_sdk_kms::Client::new(&sdk_config);
+
+    let result = client.decrypt()
+        .ciphertext_blob(Blob::new(BASE64.decode(key.enc.trim())?))
+        .set_encryption_context(key.context.clone())
+        .send().await?;
+    result.plaintext.map(Blob::into_inner).ok_or_else(|| anyhow!("No plaintext returned"))
+}
+
+/// Tries each of the file's keys in turn.
+async fn data_key(sdk_config: &SdkConfig, metadata: &Metadata, age_identities: Option<&Path>) -> Result<Vec<u8>> {
+    let mut errors = Vec::new();
+
+    for key in &metadata.kms {
+        match kms_data_key(sdk_config, key).await {
+            Ok(data_key) => return Ok(data_key),
+            Err(e) => errors.push(format!("KMS key {}: {:#}", key.arn, e))
+        }
+    }
+
+    if !metadata.age.is_empty() {
+        let path = match age_identities {
+            Some(path) => Some(path.to_owned()),
+            None => std::env::var_os("SOPS_AGE_KEY_FILE").map(PathBuf::from)
+        };
+        match path {
+            Some(path) => {
+                let identities = agekeys::load_identities(&path)?;
+                for key in &metadata.age {
+                    match agekeys::decrypt(&identities, key.enc.as_bytes()) {
+                        Ok(data_key) => return Ok(data_key),
+                        Err(e) => errors.push(format!("age: {:#}", e))
+                    }
+                }
+            }
+            None => errors.push("age: no identities, set age_identities or SOPS_AGE_KEY_FILE".to_owned())
+        }
+    }
+
+    if errors.is_empty() {
+        bail!("No KMS or age keys in the file");
+    }
+    bail!("Unable to decrypt the data key:\n  {}", errors.join("\n  "))
+}
+
+/// Decrypts `ENC[AES256_GCM,data:...,iv:...,tag:...,type:...]`, authenticated
+/// with the additional data (for values, the path of keys leading to it).
+/// Returns the plaintext with its type.
+fn decrypt_value(cipher: &SopsCipher, value: &str, aad: &str) -> Result<(String, String)> {
+    let fields: HashMap<&str, &str> = value.trim_start_matches(ENCRYPTED_PREFIX).trim_end_matches(']')
+        .split(',')
+        .filter_map(|field| field.split_once(':'))
+        .collect();
+    let field = |name: &str| fields.get(name).copied().ok_or_else(|| anyhow!("Encrypted value has no {}", name));
+
+    let mut ciphertext = BASE64.decode(field("data")?)?;
+    ciphertext.extend(BASE64.decode(field("tag")?)?);
+    let iv = BASE64.decode(field("iv")?)?;
+    if iv.len() != 32 {
+        bail!("Encrypted value has an invalid iv");
+    }
+    let plaintext = cipher.decrypt(Nonce::from_slice(&iv), Payload { msg: &ciphertext, aad: aad.as_bytes() })
+        .map_err(|_| anyhow!("Decryption failed"))?;
+    Ok((String::from_utf8_lossy(&plaintext).into_owned(), fields.get("type").copied().unwrap_or("str").to_owned()))
+}
+
+/// A decrypted value as the type it was encrypted as. Booleans are
+/// encrypted as Go formats them (True/False).
+fn typed_value(plaintext: String, value_type: &str) -> Result<Value> {
+    Ok(match value_type {
+        "bool" => match plaintext.as_str() {
+            "True" | "true" => Value::Bool(true),
+            "False" | "false" => Value::Bool(false),
+            other => bail!("Invalid bool {:?}", other)
+        },
+        "int" => Value::Number(plaintext.parse::<i64>()
+            .with_context(|| format!("Invalid int {:?}", plaintext))?.into()),
+        "float" => Value::Number(plaintext.parse::<f64>()
+            .with_context(|| format!("Invalid float {:?}", plaintext))?.into()),
+        _ => Value::String(plaintext)
+    })
+}
+
+/// The values sops hashes for the MAC, in document order.
+struct Mac {
+    hasher: Sha512,
+    only_encrypted: bool,
+}
+
+/// A value as sops hashes it, as its Go representation.
+fn mac_bytes(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Bool(b) => Some(if *b { "True" } else { "False" }.to_owned()),
+        Value::Number(n) => Some(match n.as_i64() {
+            Some(i) => i.to_string(),
+            None => n.as_f64().map(|f| f.to_string()).unwrap_or_else(|| n.to_string())
+        }),
+        _ => None
+    }
+}
+
+fn key_string(key: &Value) -> String {
+    match key {
+        Value::String(s) => s.clone(),
+        other => mac_bytes(other).unwrap_or_default()
+    }
+}
+
+/// Decrypts the values in place, hashing them for the MAC on the way.
+fn decrypt_tree(cipher: &SopsCipher, value: &mut Value, path: &mut Vec<String>, mac: &mut Mac) -> Result<()> {
+    match value {
+        Value::Mapping(map) => {
+            for (k, v) in map.iter_mut() {
+                path.push(key_string(k));
+                decrypt_tree(cipher, v, path, mac)?;
+                path.pop();
+            }
+        }
+        // List items share their parent's path
+        Value::Sequence(values) => {
+            for v in values {
+                decrypt_tree(cipher, v, path, mac)?;
+            }
+        }
+        Value::String(s) if s.starts_with(ENCRYPTED_PREFIX) => {
+            let (plaintext, value_type) = decrypt_value(cipher, s, &format!("{}:", path.join(":")))
+                .with_context(|| format!("Error decrypting {}", path.join(".")))?;
+            mac.hasher.update(plaintext.as_bytes());
+            *value = typed_value(plaintext, &value_type)
+                .with_context(|| format!("Error decrypting {}", path.join(".")))?;
+        }
+        other => if let Some(bytes) = mac_bytes(other).filter(|_| !mac.only_encrypted) {
+            mac.hasher.update(bytes.as_bytes());
+        }
+    }
+    Ok(())
+}
+
+async fn decrypt_file(sdk_config: &SdkConfig, config_dir: &Path, spec: &SopsSpec) -> Result<serde_json::Value> {
+    let path = config_dir.join(&spec.path);
+    let contents = std::fs::read(&path)
+        .with_context(|| format!("Error reading {}", path.display()))?;
+    // JSON is also valid YAML
+    // (As YAML, to keep the order the MAC is computed in)
+    let mut doc: Value = serde_yaml::from_slice(&contents)
+        .with_context(|| format!("Error parsing {}", path.display()))?;
+
+    let metadata = doc.as_mapping_mut().and_then(|map| map.remove(METADATA_KEY))
+        .ok_or_else(|| anyhow!("{} is not encrypted with sops", path.display()))?;
+    let metadata: Metadata = serde_yaml::from_value(metadata)
+        .with_context(|| format!("Error parsing sops metadata of {}", path.display()))?;
+
+    let age_identities = spec.age_identities.as_ref().map(|p| config_dir.join(p));
+    let data_key = data_key(sdk_config, &metadata, age_identities.as_deref()).await
+        .with_context(|| format!("Error decrypting {}", path.display()))?;
+    if data_key.len() != 32 {
+        bail!("Data key of {} is not 256 bits", path.display());
+    }
+    let cipher = SopsCipher::new(Key::<SopsCipher>::from_slice(&data_key));
+
+    let mut mac = Mac { hasher: Sha512::new(), only_encrypted: metadata.mac_only_encrypted };
+    decrypt_tree(&cipher, &mut doc, &mut Vec::new(), &mut mac)
+        .with_context(|| format!("Error decrypting {}", path.display()))?;
+
+    let (expected, _) = decrypt_value(&cipher, &metadata.mac, &metadata.lastmodified)
+        .with_context(|| format!("Error decrypting the MAC of {}", path.display()))?;
+    let actual: String = mac.hasher.finalize().iter().map(|b| format!("{:02X}", b)).collect();
+    if !actual.eq_ignore_ascii_case(&expected) {
+        bail!("MAC of {} doesn't match, it has been modified or corrupted", path.display());
+    }
+
+    Ok(serde_json::to_value(doc)?)
+}
+
+pub async fn get_sops_properties(sdk_config: &SdkConfig, config_dir: &Path, specs: &[SopsSpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    for spec in specs {
+        let doc = decrypt_file(sdk_config, config_dir, spec).await?;
+        flatten_value(spec.key.as_deref().unwrap_or(""), &doc, &mut data);
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::future::{try_join_all, LocalBoxFuture};
+use futures::FutureExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::keyformat::KeyPrefix;
+use crate::model::flatten_value;
+
+/// Plugins are found on the PATH as psmerge-source-<name>.
+const PLUGIN_PREFIX: &str = "psmerge-source-";
+const PLUGIN_PROTOCOL_VERSION: u32 = 1;
+const DEFAULT_PLUGIN_TIMEOUT: u64 = 60;
+
+/// Somewhere properties are fetched from.
+pub trait Source {
+    /// For messages, e.g. "Parameter Store".
+    fn name(&self) -> String;
+
+    fn fetch(&self) -> LocalBoxFuture<'_, Result<HashMap<String, String>>>;
+}
+
+/// Fetches from all of the sources at once, merged in order (later sources
+/// take precedence).
+pub async fn fetch_all(sources: &[Box<dyn Source + '_>]) -> Result<HashMap<String, String>> {
+    let results = try_join_all(sources.iter().map(|source| async move {
+        source.fetch().await.with_context(|| format!("Error fetching from {}", source.name()))
+    })).await?;
+
+    Ok(results.into_iter().flatten().collect())
+}
+
+/// A source provided by an external program, psmerge-source-<name>. It's
+/// sent `{"version": 1, "config": ..., "region": ...}` on standard input and
+/// answers with a JSON object of properties (flattened into dotted keys) on
+/// standard output, exiting non-zero on failure. Its standard error is
+/// passed through.
+#[derive(Debug, Deserialize)]
+pub struct PluginSpec {
+    name: String,
+    /// Passed to the plugin as is.
+    #[serde(default)]
+    config: Value,
+    /// In seconds, defaults to 60.
+    timeout: Option<u64>,
+    /// Prefix for its keys.
+    key: Option<String>,
+}
+
+impl KeyPrefix for PluginSpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+pub struct PluginSource<'a> {
+    pub spec: &'a PluginSpec,
+    pub region: Option<String>,
+}
+
+impl PluginSource<'_> {
+    async fn run(&self) -> Result<Value> {
+        let program = format!("{}{}", PLUGIN_PREFIX, self.spec.name);
+        let mut child = Command::new(&program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Error running {} (is it on the PATH?)", program))?;
+
+        let request = json!({
+            "version": PLUGIN_PROTOCOL_VERSION,
+            "config": self.spec.config,
+            "region": self.region,
+        });
+        let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("No stdin"))?;
+        let write = async move {
+            match stdin.write_all(request.to_string().as_bytes()).await {
+                // It needn't read the request
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+                result => result
+            }
+        };
+
+        // Written while reading its output, so neither side can block the
+        // other, all within the timeout. The child is killed if it expires.
+        let timeout = Duration::from_secs(self.spec.timeout.unwrap_or(DEFAULT_PLUGIN_TIMEOUT));
+        let (written, output) = tokio::time::timeout(timeout, async { tokio::join!(write, child.wait_with_output()) }).await
+            .map_err(|_| anyhow!("{} timed out after {}s", program, timeout.as_secs()))?;
+        written.with_context(|| format!("Error writing the request to {}", program))?;
+        let output = output?;
+        if !output.status.success() {
+            bail!("{} exited with {}", program, output.status);
+        }
+
+        let doc: Value = serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("Output of {} is not JSON", program))?;
+        if !doc.is_object() {
+            bail!("Output of {} is not a JSON object", program);
+        }
+        Ok(doc)
+    }
+}
+
+impl Source for PluginSource<'_> {
+    fn name(&self) -> String {
+        format!("plugin {}", self.spec.name)
+    }
+
+    fn fetch(&self) -> LocalBoxFuture<'_, Result<HashMap<String, String>>> {
+        async move {
+            let doc = self.run().await?;
+            let mut data = HashMap::new();
+            flatten_value(self.spec.key.as_deref().unwrap_or(""), &doc, &mut data);
+            Ok(data)
+        }.boxed_local()
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::Url;
+use rusqlite::types::ValueRef;
+use rustls::ClientConfig;
+use rustls_platform_verifier::ConfigVerifierExt;
+use serde::Deserialize;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::keyformat::KeyPrefix;
+
+const SQLITE_SCHEME: &str = "sqlite:";
+
+/// A query returning (key, value) rows, merged in as properties.
+#[derive(Debug, Deserialize)]
+pub struct QuerySpec {
+    /// `sqlite:path/to/db` (relative to the config file) or
+    /// `postgres://user@host/db`, with TLS unless `?sslmode=disable`.
+    url: String,
+    /// Environment variable holding the (Postgres) password.
+    password_env: Option<String>,
+    /// Its first two columns are the key & value.
+    query: String,
+    /// Prefix for its keys.
+    key: Option<String>,
+}
+
+impl KeyPrefix for QuerySpec {
+    fn key_prefix(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+fn sqlite_rows(path: &Path, query: &str) -> Result<Vec<(String, String)>> {
+    let db = rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Error opening {}", path.display()))?;
+    let mut statement = db.prepare(query)?;
+
+    let text = |value: ValueRef| -> Result<String> {
+        Ok(match value {
+            ValueRef::Null => String::new(),
+            ValueRef::Integer(i) => i.to_string(),
+            ValueRef::Real(f) => f.to_string(),
+            ValueRef::Text(s) | ValueRef::Blob(s) => String::from_utf8(s.to_vec())
+                .map_err(|_| anyhow!("Value is not UTF-8"))?
+        })
+    };
+
+    let mut rows = statement.query([])?;
+    let mut result = Vec::new();
+    while let Some(row) = rows.next()? {
+        result.push((text(row.get_ref(0)?)?, text(row.get_ref(1)?)?));
+    }
+    Ok(result)
+}
+
+async fn postgres_rows(spec: &QuerySpec) -> Result<Vec<(String, String)>> {
+    let mut pg_config: tokio_postgres::Config = spec.url.parse()
+        .context("Invalid Postgres URL")?;
+    if let Some(var) = &spec.password_env {
+        pg_config.password(std::env::var(var).with_context(|| format!("Environment variable {} not set", var))?);
+    }
+
+    // sslmode (prefer by default) decides whether it's used
+    let tls = MakeRustlsConnect::new(ClientConfig::with_platform_verifier()?);
+    let (client, connection) = pg_config.connect(tls).await?;
+    let connection = tokio::spawn(connection);
+
+    let rows = client.query(spec.query.as_str(), &[]).await?;
+    drop(client);
+    connection.await??;
+
+    rows.iter()
+        .map(|row| Ok((row.try_get::<_, Option<String>>(0)?.unwrap_or_default(),
+                       row.try_get::<_, Option<String>>(1)?.unwrap_or_default())))
+        .collect::<Result<_, tokio_postgres::Error>>()
+        .context("Key & value columns must be text (cast them with ::text)")
+}
+
+/// The URL without its password, if it has one, for messages.
+fn redacted(url: &str) -> String {
+    let mut url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return "(invalid URL)".to_owned()
+    };
+    if url.password().is_some() {
+        let _ = url.set_password(Some("REDACTED"));
+    }
+    if url.query_pairs().any(|(k, _)| k == "password") {
+        let pairs: Vec<(String, String)> = url.query_pairs()
+            .map(|(k, v)| { let v = if k == "password" { "REDACTED".into() } else { v.into_owned() }; (k.into_owned(), v) })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
+
+pub async fn get_sql_properties(config_dir: &Path, specs: &[QuerySpec]) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    for spec in specs {
+        let rows = if let Some(path) = spec.url.strip_prefix(SQLITE_SCHEME) {
+            let path = config_dir.join(path);
+            let query = spec.query.clone();
+            tokio::task::spawn_blocking(move || sqlite_rows(&path, &query)).await?
+        } else if spec.url.starts_with("postgres://") || spec.url.starts_with("postgresql://") {
+            postgres_rows(spec).await
+        } else {
+            bail!("Unsupported database URL {}, expected sqlite: or postgres://", redacted(&spec.url))
+        }.with_context(|| format!("Error querying {}", redacted(&spec.url)))?;
+
+        for (k, v) in rows {
+            let k = match &spec.key {
+                Some(prefix) => format!("{}.{}", prefix, k),
+                None => k
+            };
+            data.insert(k, v);
+        }
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use aws_config::SdkConfig;
+use aws_config::timeout::TimeoutConfig;
+use serde::Deserialize;
+
+/// Timeouts for AWS requests, all in milliseconds. Any not given keep the
+/// SDK's defaults.
+#[derive(Debug, Deserialize)]
+pub struct TimeoutSpec {
+    connect: Option<u64>,
+    /// Between receiving bytes of a response.
+    read: Option<u64>,
+    /// Of each attempt of a request.
+    attempt: Option<u64>,
+    /// Of a whole request, including retries.
+    operation: Option<u64>,
+    /// Of fetching everything, after which the run is abandoned as with
+    /// --timeout.
+    pub fetch: Option<u64>,
+}
+
+pub fn configure(sdk_config: &SdkConfig, spec: &TimeoutSpec) -> SdkConfig {
+    let mut builder = sdk_config.timeout_config()
+        .map(TimeoutConfig::to_builder)
+        .unwrap_or_else(TimeoutConfig::builder);
+    if let Some(ms) = spec.connect {
+        builder = builder.connect_timeout(Duration::from_millis(ms));
+    }
+    if let Some(ms) = spec.read {
+        builder = builder.read_timeout(Duration::from_millis(ms));
+    }
+    if let Some(ms) = spec.attempt {
+        builder = builder.operation_attempt_timeout(Duration::from_millis(ms));
+    }
+    if let Some(ms) = spec.operation {
+        builder = builder.operation_timeout(Duration::from_millis(ms));
+    }
+
+    sdk_config.to_builder().timeout_config(builder.build()).build()
+}
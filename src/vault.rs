@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use aws_config::SdkConfig;
+use aws_credential_types::provider::ProvideCredentials;
+use aws_sigv4::http_request::{sign, SignableBody, SignableRequest, SigningSettings};
+use aws_sigv4::sign::v4;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::model::flatten_value;
+use crate::{watch, META_KEY};
+
+const DEFAULT_TOKEN_ENV: &str = "VAULT_TOKEN";
+const DEFAULT_SECRET_ID_ENV: &str = "VAULT_SECRET_ID";
+const DEFAULT_KV_VERSION: u8 = 2;
+
+// The request the aws auth method has Vault replay to identify us
+const STS_URL: &str = "https://sts.amazonaws.com/";
+const STS_HOST: &str = "sts.amazonaws.com";
+const STS_REGION: &str = "us-east-1";
+const GET_CALLER_IDENTITY: &str = "Action=GetCallerIdentity&Version=2011-06-15";
+
+#[derive(Debug, Deserialize)]
+pub struct VaultConfig {
+    /// Vault server address, defaults to $VAULT_ADDR.
+    address: Option<String>,
+    /// Environment variable holding the Vault token.
+    token_env: Option<String>,
+    /// How to log in, rather than with the token.
+    auth: Option<AuthSpec>,
+    /// Enterprise namespace.
+    namespace: Option<String>,
+    /// KV secrets to read, merged in order.
+    #[serde(default)]
+    kv: Vec<KvSpec>,
+    /// Dynamic secrets engine paths to request leased credentials from.
+    #[serde(default)]
+    dynamic: Vec<DynamicSpec>,
+}
+
+impl VaultConfig {
+    /// The keys its secrets are placed under.
+    pub fn key_prefixes(&self) -> Vec<String> {
+        self.kv.iter().filter_map(|spec| spec.key.clone())
+            .chain(self.dynamic.iter().map(|spec| spec.key.clone()))
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum AuthSpec {
+    /// With the token in token_env (the default).
+    Token,
+    AppRole {
+        role_id: String,
+        /// Environment variable holding the secret ID, defaults to
+        /// VAULT_SECRET_ID.
+        secret_id_env: Option<String>,
+        /// Defaults to approle.
+        mount: Option<String>,
+    },
+    /// With the AWS credentials psmerge is using.
+    Aws {
+        role: String,
+        /// Defaults to aws.
+        mount: Option<String>,
+        /// Value of X-Vault-AWS-IAM-Server-ID, if the mount requires it.
+        server_id: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KvSpec {
+    /// Secrets engine mount, e.g. `secret`.
+    mount: String,
+    /// Secret path within the mount, e.g. `myapp/config`.
+    path: String,
+    /// 1 or 2, defaults to 2.
+    version: Option<u8>,
+    /// Property key the fields are placed under, if any.
+    key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DynamicSpec {
+    /// Credential endpoint, e.g. `database/creds/readonly` or `aws/sts/deploy`.
+    path: String,
+    /// Property key the credential fields are placed under. The lease is
+    /// stored as `__meta.<key>.lease_id`, `.lease_duration` & `.renewable`.
+    key: String,
+}
+
+struct VaultClient {
+    client: Client,
+    address: String,
+    token: String,
+    namespace: Option<String>,
+}
+
+#[derive(Clone)]
+struct Lease {
+    id: String,
+    /// Seconds it was issued for
+    duration: u64,
+    renewable: bool,
+    expires: Instant,
+}
+
+impl Lease {
+    fn new(id: &str, duration: u64, renewable: bool) -> Lease {
+        Lease { id: id.to_owned(), duration, renewable, expires: Instant::now() + Duration::from_secs(duration) }
+    }
+}
+
+/// Dynamic credentials with their lease, and the token that issued them
+/// (which revokes them when it expires).
+struct Credentials {
+    lease: Lease,
+    token: String,
+    creds: Value,
+}
+
+/// The login & leases kept between runs when watching, so they're renewed
+/// rather than replaced.
+#[derive(Default)]
+pub struct Leases {
+    login: Option<Lease>,
+    dynamic: HashMap<String, Credentials>,
+}
+
+/// Signs an STS GetCallerIdentity request for the aws auth method to
+/// replay, returning its login payload.
+async fn aws_login_payload(sdk_config: &SdkConfig, role: &str, server_id: Option<&str>) -> Result<Value> {
+    let credentials = sdk_config.credentials_provider()
+        .ok_or_else(|| anyhow!("No AWS credentials provider configured"))?
+        .provide_credentials().await?;
+    let identity = credentials.into();
+
+    let mut headers = vec![
+        ("host", STS_HOST),
+        ("content-type", "application/x-www-form-urlencoded; charset=utf-8"),
+    ];
+    if let Some(server_id) = server_id {
+        headers.push(("x-vault-aws-iam-server-id", server_id));
+    }
+
+    let params = v4::SigningParams::builder()
+        .identity(&identity)
+        .region(STS_REGION)
+        .name("sts")
+        .time(SystemTime::now())
+        .settings(SigningSettings::default())
+        .build()?
+        .into();
+    let signable = SignableRequest::new("POST", STS_URL, headers.iter().copied(), SignableBody::Bytes(GET_CALLER_IDENTITY.as_bytes()))?;
+    let (instructions, _signature) = sign(signable, &params)?.into_parts();
+
+    let mut signed_headers = serde_json::Map::new();
+    for (name, value) in headers.into_iter().chain(instructions.headers()) {
+        signed_headers.insert(name.to_owned(), json!([value]));
+    }
+
+    Ok(json!({
+        "role": role,
+        "iam_http_request_method": "POST",
+        "iam_request_url": BASE64.encode(STS_URL),
+        "iam_request_body": BASE64.encode(GET_CALLER_IDENTITY),
+        "iam_request_headers": BASE64.encode(Value::Object(signed_headers).to_string()),
+    }))
+}
+
+impl VaultClient {
+    async fn new(sdk_config: &SdkConfig, config: &VaultConfig, leases: &mut Leases, verbosity: u8) -> Result<VaultClient> {
+        let address = match &config.address {
+            Some(address) => address.clone(),
+            None => std::env::var("VAULT_ADDR").context("No Vault address configured and VAULT_ADDR not set")?
+        };
+
+        let mut vault = VaultClient {
+            client: Client::new(),
+            address: address.trim_end_matches('/').to_owned(),
+            token: String::new(),
+            namespace: config.namespace.clone(),
+        };
+
+        // The last run's login, renewed, so the leases it issued live on
+        if let Some(login) = leases.login.take() {
+            vault.token = login.id.clone();
+            if let Some(login) = vault.renew(&login, "auth/token/renew-self", json!({ "increment": login.duration }), "/auth/lease_duration", verbosity).await {
+                leases.login = Some(login);
+                return Ok(vault);
+            }
+        }
+
+        let (mount, payload) = match &config.auth {
+            None | Some(AuthSpec::Token) => {
+                let token_env = config.token_env.as_deref().unwrap_or(DEFAULT_TOKEN_ENV);
+                vault.token = std::env::var(token_env)
+                    .with_context(|| format!("Environment variable {} not set", token_env))?;
+                return Ok(vault);
+            }
+            Some(AuthSpec::AppRole { role_id, secret_id_env, mount }) => {
+                let secret_id_env = secret_id_env.as_deref().unwrap_or(DEFAULT_SECRET_ID_ENV);
+                let secret_id = std::env::var(secret_id_env)
+                    .with_context(|| format!("Environment variable {} not set", secret_id_env))?;
+                (mount.as_deref().unwrap_or("approle"), json!({ "role_id": role_id, "secret_id": secret_id }))
+            }
+            Some(AuthSpec::Aws { role, mount, server_id }) =>
+                (mount.as_deref().unwrap_or("aws"), aws_login_payload(sdk_config, role, server_id.as_deref()).await?)
+        };
+
+        let response = vault.login(mount, &payload).await
+            .with_context(|| format!("Failed to log in to Vault at auth/{}", mount))?;
+        vault.token = response.pointer("/auth/client_token").and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("Vault login response has no client token"))?
+            .to_owned();
+        leases.login = Some(Lease::new(&vault.token,
+            response.pointer("/auth/lease_duration").and_then(Value::as_u64).unwrap_or(0),
+            response.pointer("/auth/renewable").and_then(Value::as_bool).unwrap_or(false)));
+        Ok(vault)
+    }
+
+    async fn login(&self, mount: &str, payload: &Value) -> Result<Value> {
+        let mut request = self.client.post(format!("{}/v1/auth/{}/login", self.address, mount.trim_matches('/')))
+            .header("Content-Type", "application/json")
+            .body(payload.to_string());
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+
+        let body = request.send().await?
+            .error_for_status()?
+            .bytes().await?;
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn read(&self, path: &str) -> Result<Value> {
+        let mut request = self.client.get(format!("{}/v1/{}", self.address, path.trim_start_matches('/')))
+            .header("X-Vault-Token", &self.token);
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+
+        let body = request.send().await?
+            .error_for_status()?
+            .bytes().await?;
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn write(&self, path: &str, payload: &Value) -> Result<Value> {
+        let mut request = self.client.put(format!("{}/v1/{}", self.address, path.trim_start_matches('/')))
+            .header("X-Vault-Token", &self.token)
+            .header("Content-Type", "application/json")
+            .body(payload.to_string());
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+
+        let body = request.send().await?
+            .error_for_status()?
+            .bytes().await?;
+
+        // Revoking responds with no content
+        match body.is_empty() {
+            true => Ok(Value::Null),
+            false => Ok(serde_json::from_slice(&body)?)
+        }
+    }
+
+    /// Renews the lease for as long again as it was issued for. None if it's
+    /// (nearly) run out, so has to be replaced: it isn't renewable & has less
+    /// than half that left, or renewing fails or gives less than half that
+    /// (its max TTL is near).
+    async fn renew(&self, lease: &Lease, path: &str, payload: Value, pointer: &str, verbosity: u8) -> Option<Lease> {
+        let half = Duration::from_secs(lease.duration / 2);
+        if !lease.renewable {
+            return Some(lease.clone()).filter(|lease| lease.expires > Instant::now() + half);
+        }
+
+        let duration = match self.write(path, &payload).await {
+            Ok(response) => response.pointer(pointer).and_then(Value::as_u64).unwrap_or(0),
+            Err(e) => {
+                eprintln!("WARNING: Failed to renew Vault lease {}: {:#}", lease.id, e);
+                return None;
+            }
+        };
+        if verbosity > 0 { println!("Vault lease {} renewed for {}s", lease.id, duration); }
+        let expires = Instant::now() + Duration::from_secs(duration);
+        Some(Lease { expires, ..lease.clone() }).filter(|_| Duration::from_secs(duration) >= half)
+    }
+}
+
+async fn get_kv_secret(vault: &VaultClient, spec: &KvSpec, data: &mut HashMap<String, String>) -> Result<()> {
+    let mount = spec.mount.trim_matches('/');
+    let path = spec.path.trim_start_matches('/');
+
+    let (fields, pointer) = match spec.version.unwrap_or(DEFAULT_KV_VERSION) {
+        1 => (vault.read(&format!("{}/{}", mount, path)).await?, "/data"),
+        2 => (vault.read(&format!("{}/data/{}", mount, path)).await?, "/data/data"),
+        other => bail!("Unsupported KV version {}", other)
+    };
+
+    let fields = fields.pointer(pointer)
+        .ok_or_else(|| anyhow!("Response has no data"))?;
+    if !fields.is_object() {
+        bail!("Response data is not an object");
+    }
+
+    flatten_value(spec.key.as_deref().unwrap_or(""), fields, data);
+    Ok(())
+}
+
+async fn issue_credentials(vault: &VaultClient, spec: &DynamicSpec, verbosity: u8) -> Result<Credentials> {
+    let response = vault.read(&spec.path).await?;
+
+    let creds = response.get("data")
+        .ok_or_else(|| anyhow!("Response has no data"))?;
+    if !creds.is_object() {
+        bail!("Response data is not an object");
+    }
+
+    let lease_id = response.get("lease_id").and_then(Value::as_str).unwrap_or("");
+    let lease_duration = response.get("lease_duration").and_then(Value::as_u64).unwrap_or(0);
+    let renewable = response.get("renewable").and_then(Value::as_bool).unwrap_or(false);
+    if verbosity > 0 { println!("Vault lease {} issued for {}s", lease_id, lease_duration); }
+
+    Ok(Credentials { lease: Lease::new(lease_id, lease_duration, renewable), token: vault.token.clone(), creds: creds.clone() })
+}
+
+/// Gets credentials, or renews the lease on those from the last run (if
+/// issued with the same token) so they stay the same. New ones replace them
+/// once it can't be renewed any further, & the old lease is revoked.
+async fn get_dynamic_secret(vault: &VaultClient, spec: &DynamicSpec, leases: &mut Leases, data: &mut HashMap<String, String>, verbosity: u8) -> Result<()> {
+    let previous = leases.dynamic.remove(&spec.path);
+    let renewed = match previous.as_ref().filter(|previous| previous.token == vault.token) {
+        Some(previous) => vault.renew(&previous.lease, "sys/leases/renew",
+            json!({ "lease_id": previous.lease.id, "increment": previous.lease.duration }), "/lease_duration", verbosity).await,
+        None => None
+    };
+    let credentials = match (renewed, previous) {
+        (Some(lease), Some(previous)) => Credentials { lease, ..previous },
+        (_, previous) => {
+            let credentials = issue_credentials(vault, spec, verbosity).await?;
+            if let Some(previous) = previous.filter(|previous| !previous.lease.id.is_empty()) {
+                if let Err(e) = vault.write("sys/leases/revoke", &json!({ "lease_id": previous.lease.id })).await {
+                    eprintln!("WARNING: Failed to revoke Vault lease {}: {:#}", previous.lease.id, e);
+                }
+            }
+            credentials
+        }
+    };
+
+    flatten_value(&spec.key, &credentials.creds, data);
+    let mut insert = |field: &str, value: String| { data.insert(format!("{}.{}.{}", META_KEY, spec.key, field), value); };
+    insert("lease_id", credentials.lease.id.clone());
+    insert("lease_duration", credentials.lease.duration.to_string());
+    insert("renewable", credentials.lease.renewable.to_string());
+    leases.dynamic.insert(spec.path.clone(), credentials);
+    Ok(())
+}
+
+/// Reads the KV secrets & gets the dynamic credentials. When watching, the
+/// login & leases are kept for the next run to renew.
+pub async fn get_vault_properties(sdk_config: &SdkConfig, config: Option<&VaultConfig>, verbosity: u8,
+                                  watch: Option<watch::Scope<'_>>) -> Result<HashMap<String, String>> {
+    let config = match config {
+        Some(config) => config,
+        None => return Ok(HashMap::new())
+    };
+
+    let mut leases = watch.map(|watch| watch.take_vault_leases()).unwrap_or_default();
+    let result = get_vault_data(sdk_config, config, &mut leases, verbosity).await;
+    if let Some(watch) = watch {
+        watch.set_vault_leases(leases);
+    }
+    result
+}
+
+async fn get_vault_data(sdk_config: &SdkConfig, config: &VaultConfig, leases: &mut Leases, verbosity: u8) -> Result<HashMap<String, String>> {
+    let mut data = HashMap::new();
+
+    let vault = VaultClient::new(sdk_config, config, leases, verbosity).await?;
+
+    for spec in &config.kv {
+        get_kv_secret(&vault, spec, &mut data).await
+            .with_context(|| format!("Failed to read Vault secret {}/{}", spec.mount, spec.path))?;
+    }
+
+    for spec in &config.dynamic {
+        get_dynamic_secret(&vault, spec, leases, &mut data, verbosity).await
+            .with_context(|| format!("Failed to get Vault credentials {}", spec.path))?;
+    }
+
+    Ok(data)
+}
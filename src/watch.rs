@@ -0,0 +1,98 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use aws_sdk_ssm::types::Parameter;
+
+use crate::{vault, SecretValue};
+
+/// Keys added, removed or whose value changed since the last run. As the
+/// metadata is kept under `__meta`, new parameter & secret versions show up
+/// here even when their values are the same.
+pub fn changed_keys(previous: &HashMap<String, String>, current: &HashMap<String, String>) -> HashSet<String> {
+    previous.keys().filter(|k| !current.contains_key(*k))
+        .chain(current.iter().filter(|(k, v)| previous.get(*k) != Some(*v)).map(|(k, _)| k))
+        .cloned()
+        .collect()
+}
+
+fn within(key: &str, parent: &str) -> bool {
+    key.strip_prefix(parent).is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Whether a template referring to these keys (or the whole model if None)
+/// is affected by the changes: a changed key is one of them, or within (or
+/// containing) one of them.
+pub fn affected(changed: &HashSet<String>, references: Option<&[String]>) -> bool {
+    match references {
+        Some(references) => changed.iter()
+            .any(|key| references.iter().any(|r| key == r || within(key, r) || within(r, key))),
+        None => !changed.is_empty()
+    }
+}
+
+/// What was fetched last time round, when watching, so that only what
+/// changed since has to be fetched again.
+#[derive(Default)]
+pub struct State {
+    /// Parameters by name, by the prefix (or list of parameters) they're from
+    parameters: Mutex<HashMap<String, HashMap<String, Parameter>>>,
+    /// Secret values with their version IDs, by secret & stage
+    secrets: Mutex<HashMap<String, (String, SecretValue)>>,
+    /// The Vault login & dynamic credentials' leases
+    vault_leases: Mutex<vault::Leases>,
+}
+
+/// Where a source keeps what it fetched: the job's state, within the account
+/// it's fetched from (if another).
+#[derive(Clone, Copy)]
+pub struct Scope<'a> {
+    state: &'a State,
+    account: &'a str,
+}
+
+impl<'a> Scope<'a> {
+    pub fn new(state: &'a State, account: &'a str) -> Scope<'a> {
+        Scope { state, account }
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}/{}", self.account, key)
+    }
+
+    pub fn parameters(&self, key: &str) -> Option<HashMap<String, Parameter>> {
+        self.state.parameters.lock().unwrap().get(&self.key(key)).cloned()
+    }
+
+    /// Replaces the parameters kept under the key.
+    pub fn set_parameters(&self, key: &str, parameters: HashMap<String, Parameter>) {
+        self.state.parameters.lock().unwrap().insert(self.key(key), parameters);
+    }
+
+    /// Adds to (or updates) the parameters kept under the key.
+    pub fn add_parameters(&self, key: &str, parameters: impl IntoIterator<Item = (String, Parameter)>) {
+        self.state.parameters.lock().unwrap().entry(self.key(key)).or_default().extend(parameters);
+    }
+
+    pub fn secret(&self, key: &str) -> Option<(String, SecretValue)> {
+        self.state.secrets.lock().unwrap().get(&self.key(key)).cloned()
+    }
+
+    pub fn set_secret(&self, key: &str, version_id: String, value: SecretValue) {
+        self.state.secrets.lock().unwrap().insert(self.key(key), (version_id, value));
+    }
+
+    /// The Vault leases, to put back once renewed (or replaced).
+    pub fn take_vault_leases(&self) -> vault::Leases {
+        std::mem::take(&mut self.state.vault_leases.lock().unwrap())
+    }
+
+    pub fn set_vault_leases(&self, leases: vault::Leases) {
+        *self.state.vault_leases.lock().unwrap() = leases;
+    }
+
+    /// Whether any secrets were fetched last time round.
+    pub fn has_secrets(&self) -> bool {
+        let prefix = self.key("");
+        self.state.secrets.lock().unwrap().keys().any(|key| key.starts_with(&prefix))
+    }
+}
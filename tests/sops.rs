@@ -0,0 +1,73 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sops");
+
+/// A scratch directory with the fixture, a config and a template using it.
+fn setup(name: &str, secrets: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("psmerge-sops-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy(Path::new(FIXTURES).join("age.key"), dir.join("age.key")).unwrap();
+    std::fs::write(dir.join("secrets.sops.yaml"), secrets).unwrap();
+    std::fs::write(dir.join("config.yaml"), "\
+sops:
+  - path: secrets.sops.yaml
+    age_identities: age.key
+templates:
+  - src: template.hbs
+    out: out.txt
+").unwrap();
+    std::fs::write(dir.join("template.hbs"),
+        "{{db.host}}:{{db.port}} {{db.password}} {{tags.[0]}},{{tags.[1]}} {{enabled}} {{port_unencrypted}}").unwrap();
+    dir
+}
+
+fn run(dir: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_psmerge"))
+        .arg("config.yaml")
+        .current_dir(dir)
+        .env("AWS_REGION", "us-east-1")
+        .env("AWS_ACCESS_KEY_ID", "test")
+        .env("AWS_SECRET_ACCESS_KEY", "test")
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap()
+}
+
+fn fixture() -> String {
+    std::fs::read_to_string(Path::new(FIXTURES).join("secrets.sops.yaml")).unwrap()
+}
+
+#[test]
+fn decrypts_and_verifies_mac() {
+    let dir = setup("ok", &fixture());
+    let output = run(&dir);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read_to_string(dir.join("out.txt")).unwrap(),
+        "db.internal:5432 hunter2 blue,green true 6543");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rejects_modified_file() {
+    let secrets = fixture().replace("port_unencrypted: 6543", "port_unencrypted: 6544");
+    let dir = setup("modified", &secrets);
+    let output = run(&dir);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("doesn't match"));
+    assert!(!dir.join("out.txt").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rejects_reordered_list() {
+    let fixture = fixture();
+    let mut lines: Vec<&str> = fixture.lines().collect();
+    let first = lines.iter().position(|l| l.starts_with("    - ENC[")).unwrap();
+    lines.swap(first, first + 1);
+    let dir = setup("reordered", &(lines.join("\n") + "\n"));
+    let output = run(&dir);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("doesn't match"));
+    std::fs::remove_dir_all(dir).unwrap();
+}